@@ -0,0 +1,192 @@
+//! Compares the pooled octree in `Chunk` with the boxed octree it replaced, where every voxel was its own allocation.
+//! Run with `cargo bench --bench octree`.
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use artewald_engine_lib::{threadpool::ThreadPool, voxel::{Chunk, Voxel}};
+use nalgebra::{Vector2, Vector3, Vector4};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const DEPTH: u32 = 7;
+const EDITS: usize = 200;
+const RUNS: u32 = 5;
+
+type Edit = (Vector3<Vector2<u32>>, Option<Vector4<f32>>);
+
+/// The octree as it was stored before the voxels were moved into a pool.
+#[derive(Clone)]
+struct BoxedVoxel {
+    pos: Vector3<f32>,
+    range: f32,
+    color: Vector4<f32>,
+    children: [Option<Box<BoxedVoxel>>; 8],
+    /// Set for the voxels an edit went through, like `Voxel::dirty`, so that only their colors are averaged again.
+    dirty: bool,
+}
+
+fn overlapping(a: Vector2<u32>, b: Vector2<u32>) -> bool {
+    a.x < b.y && b.x < a.y
+}
+
+impl BoxedVoxel {
+    fn new(pos: Vector3<f32>, range: f32, color: Vector4<f32>) -> BoxedVoxel {
+        BoxedVoxel { pos, range, color, children: Default::default(), dirty: true }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.iter().all(|child| child.is_none())
+    }
+
+    fn covered_by(&self, range: Vector3<Vector2<u32>>) -> bool {
+        (0..3).all(|axis| range[axis].x as f32 <= self.pos[axis] && self.pos[axis] + self.range <= range[axis].y as f32)
+    }
+
+    fn set(&mut self, depth: u32, current_depth: u32, range: Vector3<Vector2<u32>>, color: Option<Vector4<f32>>) -> bool {
+        self.dirty = true;
+        if depth == current_depth || (current_depth > 0 && self.covered_by(range)) {
+            match color {
+                Some(color) => {
+                    self.color = color;
+                    self.children = Default::default();
+                    return false;
+                },
+                None => return current_depth > 0,
+            }
+        }
+
+        let size = self.range/2.0;
+        for i in 0..8 {
+            let (x, y, z) = ((i % 2) as f32, (i / 4) as f32, ((i / 2) % 2) as f32);
+            let pos = Vector3::new(self.pos.x + x * size, self.pos.y + y * size, self.pos.z + z * size);
+            if !(0..3).all(|axis| overlapping(range[axis], Vector2::new(pos[axis] as u32, (pos[axis] + size) as u32))) {
+                continue;
+            }
+            match self.children[i].as_deref_mut() {
+                None if color.is_none() => continue,
+                None => self.children[i] = Some(Box::new(BoxedVoxel::new(pos, size, Vector4::zeros()))),
+                Some(child) if child.is_leaf() && current_depth + 1 < depth => {
+                    let color = child.color;
+                    for j in 0..8 {
+                        let (x, y, z) = ((j % 2) as f32, (j / 4) as f32, ((j / 2) % 2) as f32);
+                        let half = size/2.0;
+                        child.children[j] = Some(Box::new(BoxedVoxel::new(Vector3::new(pos.x + x * half, pos.y + y * half, pos.z + z * half), half, color)));
+                    }
+                },
+                Some(_) => (),
+            }
+            if self.children[i].as_deref_mut().unwrap().set(depth, current_depth + 1, range, color) {
+                self.children[i] = None;
+            }
+        }
+
+        if self.is_leaf() {
+            self.color = Vector4::zeros();
+            return current_depth > 0;
+        }
+        false
+    }
+
+    /// Averages the colors of the voxels that have been edited, the same way `Chunk` does.
+    fn recalculate_colors(&mut self) -> (Vector4<f32>, f32) {
+        let volume = self.range * self.range * self.range;
+        if self.is_leaf() || !self.dirty {
+            return (self.color, volume);
+        }
+        self.dirty = false;
+        let mut weights = vec![];
+        for i in 0..8 {
+            weights.push(match self.children[i].as_deref_mut() {
+                Some(child) => child.recalculate_colors(),
+                None => (Vector4::zeros(), volume/8.0),
+            });
+        }
+        let total: f32 = weights.iter().map(|(_, weight)| weight).sum();
+        self.color = weights.iter().map(|(color, weight)| color * (weight/total)).sum();
+        (self.color, volume)
+    }
+
+    fn collect_leaves<'a>(&'a self, current_depth: u32, leaves: &mut Vec<&'a BoxedVoxel>) {
+        if self.is_leaf() {
+            if current_depth > 0 {
+                leaves.push(self);
+            }
+            return;
+        }
+        for child in self.children.iter().flatten() {
+            child.collect_leaves(current_depth + 1, leaves);
+        }
+    }
+
+    fn get_leaves(&self) -> Vec<&BoxedVoxel> {
+        let mut leaves = vec![];
+        self.collect_leaves(0, &mut leaves);
+        leaves
+    }
+
+    fn sum_colors(&self) -> Vector4<f32> {
+        self.color + self.children.iter().flatten().map(|child| child.sum_colors()).sum::<Vector4<f32>>()
+    }
+}
+
+fn sum_colors(chunk: &Chunk, voxel: &Voxel) -> Vector4<f32> {
+    voxel.color(chunk.materials()) + chunk.voxels().children(voxel).map(|child| sum_colors(chunk, child)).sum::<Vector4<f32>>()
+}
+
+fn random_edits() -> Vec<Edit> {
+    let mut rng = StdRng::seed_from_u64(4230);
+    let cell_size = artewald_engine_lib::voxel::CHUNKSIZE/2_u32.pow(DEPTH);
+    let cells = 2_u32.pow(DEPTH);
+    (0..EDITS).map(|i| {
+        let mut axis = || {
+            let start = rng.gen_range(0..cells);
+            Vector2::new(start, (start + rng.gen_range(1..cells/4)).min(cells)) * cell_size
+        };
+        let range = Vector3::new(axis(), axis(), axis());
+        // Every fourth edit carves a hole
+        let color = if i % 4 == 3 { None } else { Some(Vector4::new(rng.gen(), rng.gen(), rng.gen(), 1.0)) };
+        (range, color)
+    }).collect()
+}
+
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        black_box(f());
+        best = best.min(start.elapsed());
+    }
+    best
+}
+
+fn report(name: &str, pooled: Duration, boxed: Duration) {
+    println!("{:<10} pooled {:>10.3?}   boxed {:>10.3?}   {:.2}x", name, pooled, boxed, boxed.as_secs_f64()/pooled.as_secs_f64());
+}
+
+fn main() {
+    let thread_pool = ThreadPool::new(None);
+    let edits = random_edits();
+
+    let build_pooled = || {
+        let mut chunk = Chunk::new(Vector2::new(0, 0), DEPTH);
+        chunk.set_voxels_batch(thread_pool.clone(), edits.iter().copied());
+        chunk
+    };
+    let build_boxed = || {
+        let mut root = BoxedVoxel::new(Vector3::zeros(), artewald_engine_lib::voxel::CHUNKSIZE as f32, Vector4::zeros());
+        for (range, color) in edits.iter() {
+            root.set(DEPTH, 0, *range, *color);
+        }
+        root.recalculate_colors();
+        root
+    };
+
+    let chunk = build_pooled();
+    let root = build_boxed();
+    assert_eq!(chunk.get_leaves().len(), root.get_leaves().len(), "both octrees should hold the same voxels");
+    println!("{} edits at depth {}, {} voxels, best of {} runs", EDITS, DEPTH, chunk.voxels().len(), RUNS);
+
+    report("build", time(build_pooled), time(build_boxed));
+    report("clone", time(|| chunk.clone()), time(|| root.clone()));
+    report("leaves", time(|| chunk.get_leaves().len()), time(|| root.get_leaves().len()));
+    report("traverse", time(|| sum_colors(&chunk, chunk.start_voxel())), time(|| root.sum_colors()));
+}
@@ -1,2 +1,4 @@
 pub mod threadpool;
-pub mod voxel;
\ No newline at end of file
+pub mod voxel;
+pub mod world;
+pub mod worldgen;
\ No newline at end of file
@@ -1,57 +1,391 @@
-use std::{thread::available_parallelism, sync::{Arc, RwLock}};
+use std::{
+    any::Any,
+    cell::Cell,
+    collections::VecDeque,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{atomic::{self, AtomicBool, AtomicUsize, Ordering}, Arc, Condvar, Mutex},
+    thread::{self, available_parallelism},
+};
 
-#[derive(Debug, Clone, Copy)]
-pub enum ThreadState {
-    Free,
-    Used,
-    Done,
-}
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// The first panic of the scoped jobs that nobody joined, which the scope passes on when it ends.
+type Panic = Arc<Mutex<Option<Box<dyn Any + Send>>>>;
 
-#[derive(Debug, Clone)]
-pub struct ThreadPoolHelper {
-    max_num_threads: usize,
+thread_local! {
+    /// The pool and the index of the worker the current thread is, if it is a worker.
+    static WORKER: Cell<Option<(*const Shared, usize)>> = const { Cell::new(None) };
+}
 
-    num_utilized: Arc<RwLock<usize>>,
+/// The state shared between the pool handles and the workers.
+struct Shared {
+    /// Jobs spawned from threads outside of the pool.
+    injector: Mutex<VecDeque<Job>>,
+    /// The jobs spawned by each worker. A worker takes its newest job first, and other workers steal the oldest ones.
+    queues: Vec<Mutex<VecDeque<Job>>>,
+    shutdown: AtomicBool,
+    sleep: Mutex<()>,
+    /// Wakes a worker when a job is pushed.
+    wake: Condvar,
+    /// Wakes the threads in `help_until` when a job is pushed or has finished.
+    helpers: Condvar,
+    /// The number of threads waiting on `helpers`.
+    waiting_helpers: AtomicUsize,
 }
 
-#[allow(dead_code)]
-impl ThreadPoolHelper {
-    pub fn new(max_num_threads: Option<usize>) -> Arc<RwLock<ThreadPoolHelper>> {
-        let threads = max_num_threads.unwrap_or(available_parallelism().unwrap().get());
-        if  threads > available_parallelism().unwrap().get() {
-            panic!("ThreadPoolHelper::new() -> You cannot have more threads than you have: you specified {}, but only {} are available.", threads, available_parallelism().unwrap().get());
+impl Shared {
+    fn current_worker(self: &Arc<Self>) -> Option<usize> {
+        WORKER.with(|worker| match worker.get() {
+            Some((pool, index)) if pool == Arc::as_ptr(self) => Some(index),
+            _ => None,
+        })
+    }
+
+    fn push(self: &Arc<Self>, job: Job) {
+        match self.current_worker() {
+            Some(index) => self.queues[index].lock().unwrap().push_back(job),
+            None => self.injector.lock().unwrap().push_back(job),
         }
-        Arc::new(RwLock::new(ThreadPoolHelper { max_num_threads: threads, num_utilized: Arc::new(RwLock::new(0))}))
+        // Taking the lock makes sure a worker that just found no jobs is waiting before it is woken
+        let _guard = self.sleep.lock().unwrap();
+        self.wake.notify_one();
+        self.helpers.notify_all();
     }
 
-    /// Returns true if a thread was started, false if not
-    pub fn try_starting_thread(&self) -> bool {
-        if self.max_num_threads <= *self.num_utilized.clone().read().unwrap() {
-            return false;
+    /// Runs a job and wakes the threads waiting in `help_until`, since a job finishing is the only thing that changes what they wait for.
+    fn run(&self, job: Job) {
+        job();
+        // Pairs with the fence in `help_until`, so that either the helper sees the new state or it is counted here
+        atomic::fence(Ordering::SeqCst);
+        if self.waiting_helpers.load(Ordering::SeqCst) > 0 {
+            let _guard = self.sleep.lock().unwrap();
+            self.helpers.notify_all();
         }
-        *self.num_utilized.clone().write().unwrap() += 1;
-        // if self.max_num_threads <= *self.num_utilized.clone().read().unwrap() {
-        //     *self.num_utilized.clone().write().unwrap() -= 1;
-        //     return false;
-        // }
-        true
     }
 
-    pub fn end_thread(&self) {
-        if *self.num_utilized.clone().read().unwrap() == 0 {
-            panic!("ThreadPoolHelper::end_thread() -> Something went horribly wrong. There are no threads to end!")
+    /// Takes the newest job of the current worker, or steals the oldest job from the others.
+    fn find_job(self: &Arc<Self>) -> Option<Job> {
+        let own = self.current_worker();
+        if let Some(index) = own {
+            if let Some(job) = self.queues[index].lock().unwrap().pop_back() {
+                return Some(job);
+            }
+        }
+        if let Some(job) = self.injector.lock().unwrap().pop_front() {
+            return Some(job);
+        }
+        let start = own.map_or(0, |index| index + 1);
+        (0..self.queues.len())
+            .map(|i| (start + i) % self.queues.len())
+            .filter(|i| Some(*i) != own)
+            .find_map(|i| self.queues[i].lock().unwrap().pop_front())
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.lock().unwrap().is_empty() || self.queues.iter().any(|queue| !queue.lock().unwrap().is_empty())
+    }
+
+    fn run_worker(self: Arc<Self>, index: usize) {
+        WORKER.with(|worker| worker.set(Some((Arc::as_ptr(&self), index))));
+        loop {
+            if let Some(job) = self.find_job() {
+                self.run(job);
+                continue;
+            }
+            let guard = self.sleep.lock().unwrap();
+            // The queues are emptied before the workers stop, so that no spawned job is lost
+            if self.has_jobs() {
+                continue;
+            }
+            if self.shutdown.load(Ordering::Acquire) {
+                break;
+            }
+            drop(self.wake.wait(guard).unwrap());
         }
-        *self.num_utilized.clone().write().unwrap() -= 1;
+        WORKER.with(|worker| worker.set(None));
     }
 
-    pub fn available_threads(&self) -> usize {
-        if self.max_num_threads < *self.num_utilized.clone().read().unwrap() {
-            panic!("There are more utilized/started threads then the max amount");
+    /// Runs other jobs until `done` returns true. What `done` checks may only be changed by jobs of this pool.
+    /// Helping out instead of only waiting is what keeps recursive jobs waiting for their own jobs from using up all the workers.
+    fn help_until(self: &Arc<Self>, done: impl Fn() -> bool) {
+        loop {
+            if done() {
+                return;
+            }
+            if let Some(job) = self.find_job() {
+                self.run(job);
+                continue;
+            }
+            let guard = self.sleep.lock().unwrap();
+            self.waiting_helpers.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            if !done() && !self.has_jobs() {
+                drop(self.helpers.wait(guard).unwrap());
+            }
+            self.waiting_helpers.fetch_sub(1, Ordering::SeqCst);
         }
-        self.max_num_threads - *self.num_utilized.clone().read().unwrap()
     }
-    
-    pub fn print_num_utilized(&self) {
-        println!("Num threads utilized: {}", *self.num_utilized.clone().read().unwrap());
+}
+
+/// The owner of the worker threads, the workers are stopped by `ThreadPool::shutdown` or when the last `ThreadPool` handle is dropped.
+struct Workers {
+    shared: Arc<Shared>,
+    num_threads: usize,
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+impl Workers {
+    fn shutdown(&self) {
+        {
+            let _guard = self.shared.sleep.lock().unwrap();
+            self.shared.shutdown.store(true, Ordering::Release);
+            self.shared.wake.notify_all();
+        }
+        // A job holding the last handle can end up dropping the pool on a worker, which cannot wait for itself
+        let current = thread::current().id();
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for handle in threads {
+            if handle.thread().id() != current {
+                let _ = handle.join();
+            }
+        }
+        // Without workers the jobs nobody waited for are run here
+        while let Some(job) = self.shared.find_job() {
+            self.shared.run(job);
+        }
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shutdown();
     }
-}
\ No newline at end of file
+}
+
+/// A fixed number of worker threads that run jobs, where workers without jobs steal from the others.
+/// Cloning the pool only clones the handle, every clone uses the same workers.
+///
+/// A queued job that holds a clone of the pool keeps the pool alive, so a pool whose jobs never run, like a detached job on a pool
+/// with 0 threads, is only stopped by `shutdown`.
+///
+/// A pool with 0 threads runs every job on the thread that waits for it, so the results are the same as with threads.
+#[derive(Clone)]
+pub struct ThreadPool {
+    workers: Arc<Workers>,
+}
+
+impl std::fmt::Debug for ThreadPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ThreadPool").field("num_threads", &self.num_threads()).finish()
+    }
+}
+
+/// The result of a job.
+struct Slot<T> {
+    result: Mutex<Option<thread::Result<T>>>,
+    /// Where the panic goes if nobody joined the job, for scoped jobs.
+    unjoined_panic: Option<Panic>,
+}
+
+impl<T> Slot<T> {
+    fn new(unjoined_panic: Option<Panic>) -> Arc<Slot<T>> {
+        Arc::new(Slot { result: Mutex::new(None), unjoined_panic })
+    }
+
+    fn run(&self, f: impl FnOnce() -> T) {
+        let result = panic::catch_unwind(AssertUnwindSafe(f));
+        *self.result.lock().unwrap() = Some(result);
+    }
+
+    fn wait(&self, shared: &Arc<Shared>) -> thread::Result<T> {
+        shared.help_until(|| self.result.lock().unwrap().is_some());
+        self.result.lock().unwrap().take().unwrap()
+    }
+}
+
+impl<T> Drop for Slot<T> {
+    fn drop(&mut self) {
+        // The result is still here if the job panicked and was never joined
+        if let (Some(unjoined_panic), Ok(Some(Err(payload)))) = (&self.unjoined_panic, self.result.get_mut().map(Option::take)) {
+            unjoined_panic.lock().unwrap().get_or_insert(payload);
+        }
+    }
+}
+
+/// A handle to wait for a job spawned with `ThreadPool::spawn`. Dropping it lets the job run on without anyone waiting for it.
+pub struct JoinHandle<T> {
+    pool: ThreadPool,
+    slot: Arc<Slot<T>>,
+}
+
+impl<T> std::fmt::Debug for JoinHandle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JoinHandle").field("finished", &self.is_finished()).finish()
+    }
+}
+
+impl<T> JoinHandle<T> {
+    /// Waits for the job to finish, running other jobs in the meantime. Returns an error with the panic if the job panicked.
+    pub fn join(self) -> thread::Result<T> {
+        self.slot.wait(&self.pool.workers.shared)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.slot.result.lock().unwrap().is_some()
+    }
+}
+
+/// Jobs spawned in a scope can borrow from outside of it, since the scope waits for all of them to finish before it returns.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: ThreadPool,
+    /// The number of jobs that have not finished yet.
+    running: Arc<AtomicUsize>,
+    unjoined_panic: Panic,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// A handle to wait for a job spawned with `Scope::spawn`.
+pub struct ScopedJoinHandle<'scope, T> {
+    pool: ThreadPool,
+    slot: Arc<Slot<T>>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, T> ScopedJoinHandle<'scope, T> {
+    /// Waits for the job to finish, running other jobs in the meantime. Returns an error with the panic if the job panicked.
+    pub fn join(self) -> thread::Result<T> {
+        self.slot.wait(&self.pool.workers.shared)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.slot.result.lock().unwrap().is_some()
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub fn spawn<F, T>(&'scope self, f: F) -> ScopedJoinHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let slot = Slot::new(Some(self.unjoined_panic.clone()));
+        let job_slot = slot.clone();
+        let running = self.running.clone();
+        running.fetch_add(1, Ordering::SeqCst);
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            job_slot.run(f);
+            // The slot has to be dropped before the scope can end, since it may hold borrowed data
+            drop(job_slot);
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        // SAFETY: `ThreadPool::scope` does not return before every job spawned in it has finished, even if it panics,
+        // so nothing borrowed by the job goes away while the job can still use it.
+        let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.workers.shared.push(job);
+        ScopedJoinHandle { pool: self.pool.clone(), slot, scope: PhantomData }
+    }
+}
+
+impl ThreadPool {
+    /// Starts a pool with the specified number of worker threads, or one per available core if it is `None`.
+    pub fn new(num_threads: Option<usize>) -> ThreadPool {
+        let num_threads = num_threads.unwrap_or_else(|| available_parallelism().map_or(1, |n| n.get()));
+        let shared = Arc::new(Shared {
+            injector: Mutex::new(VecDeque::new()),
+            queues: (0..num_threads).map(|_| Mutex::new(VecDeque::new())).collect(),
+            shutdown: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            helpers: Condvar::new(),
+            waiting_helpers: AtomicUsize::new(0),
+        });
+        let threads = (0..num_threads).map(|index| {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name(format!("artewald-worker-{}", index))
+                .spawn(move || shared.run_worker(index))
+                .expect("ThreadPool::new(): Failed to start a worker thread")
+        }).collect();
+        ThreadPool { workers: Arc::new(Workers { shared, num_threads, threads: Mutex::new(threads) }) }
+    }
+
+    /// The number of worker threads the pool was started with, which stays the same after `shutdown`.
+    pub fn num_threads(&self) -> usize {
+        self.workers.num_threads
+    }
+
+    /// Stops the workers once they have run every queued job, without waiting for the last handle to be dropped.
+    /// The pool can still be used afterwards, but like a pool with 0 threads.
+    pub fn shutdown(&self) {
+        self.workers.shutdown();
+    }
+
+    /// Runs `f` on the pool and returns a handle to get the result with.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let slot = Slot::new(None);
+        let job_slot = slot.clone();
+        self.workers.shared.push(Box::new(move || job_slot.run(f)));
+        JoinHandle { pool: self.clone(), slot }
+    }
+
+    /// Runs `f` with a scope that jobs borrowing from the current stack can be spawned in, and waits for all of them before returning.
+    /// If `f` panics, or a job that was not joined panics, the first of those panics is passed on after the jobs have finished.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self.clone(),
+            running: Arc::new(AtomicUsize::new(0)),
+            unjoined_panic: Arc::new(Mutex::new(None)),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        self.workers.shared.help_until(|| scope.running.load(Ordering::SeqCst) == 0);
+        let unjoined_panic = scope.unjoined_panic.lock().unwrap().take();
+        match (result, unjoined_panic) {
+            (Err(payload), _) | (Ok(_), Some(payload)) => panic::resume_unwind(payload),
+            (Ok(result), None) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unjoined_scoped_panics_are_passed_on() {
+        let pool = ThreadPool::new(Some(2));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| pool.scope(|scope| {
+            scope.spawn(|| panic!("first"));
+        })));
+        assert_eq!(result.unwrap_err().downcast_ref::<&str>(), Some(&"first"));
+
+        // A joined panic has already been handed to whoever joined the job
+        let joined = pool.scope(|scope| scope.spawn(|| panic!("joined")).join().is_err());
+        assert!(joined);
+    }
+
+    #[test]
+    fn shutdown_runs_the_queued_jobs() {
+        let pool = ThreadPool::new(Some(0));
+        let ran = Arc::new(AtomicBool::new(false));
+        let (job_pool, job_ran) = (pool.clone(), ran.clone());
+        drop(pool.spawn(move || {
+            job_ran.store(true, Ordering::SeqCst);
+            drop(job_pool);
+        }));
+        pool.shutdown();
+        assert!(ran.load(Ordering::SeqCst));
+        assert_eq!(Arc::strong_count(&pool.workers), 1);
+        assert_eq!(pool.spawn(|| 4).join().unwrap(), 4);
+    }
+}
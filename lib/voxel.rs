@@ -1,21 +1,41 @@
-use std::{sync::{Arc, RwLock}, thread::JoinHandle};
-use std::thread;
+
+use std::collections::HashMap;
 
 use bytemuck::{Pod, Zeroable};
 use nalgebra::{Vector2, Vector4, Vector3, Vector};
 
-use crate::voxel::math::{distance_between_points, view_cm_size};
-use crate::threadpool::ThreadPoolHelper;
+use crate::voxel::math::{distance_to_box, view_cm_size};
+use crate::threadpool::{JoinHandle, ThreadPool};
 
+use self::compact::{pack_color, unpack_color};
+use self::frustum::Frustum;
+use self::material::{Material, MaterialIndex, MaterialTable, NO_MATERIAL};
 use self::math::{vec2_one_d_lenght, mul_vector4, vec2_one_d_in_range, vec2_one_d_overlapping};
+use self::pool::{VoxelIndex, VoxelPool};
 
 //use self::math::{Vec2, Vec4, Vec3};
 
+pub mod compact;
+pub mod dag;
+pub mod frustum;
+pub mod material;
 pub mod math;
+pub mod mesh;
+pub mod pool;
+pub mod raycast;
+pub mod render;
+pub mod serialization;
+pub mod vox;
+pub mod voxelize;
 
 // Constants
 pub const CHUNKPOWER: u32 = 8;
 pub const CHUNKSIZE: u32 = (4 as u32).pow(CHUNKPOWER);
+/// The start voxel is the first voxel added to a chunk's pool, and it is never removed.
+const START_VOXEL: VoxelIndex = 0;
+/// Voxels above this depth hand their children to the thread pool, below it the children are handled by the same thread.
+/// Deeper voxels have too little work to be worth a job, and every level adds to the copying done by `traverse_and_color`.
+const PARALLEL_DEPTH: u32 = 2;
 
 // Structs
 #[derive(Debug, Clone)]
@@ -25,8 +45,15 @@ pub struct Voxel {
     // pub x_range: Vector2<f32>,
     // pub y_range: Vector2<f32>,
     // pub z_range: Vector2<f32>,
-    pub color: Vector4<f32>,
-    pub children: [Option<Box<Voxel>>; 8]
+    /// The averaged color of the children, for the voxels without a material. Solid voxels get their color from their material, see `Voxel::color`.
+    pub averaged_color: Vector4<f32>,
+    /// The material of a solid voxel in the chunk's `MaterialTable`. Voxels with children only have an averaged color and no material.
+    pub material: Option<MaterialIndex>,
+    /// Where the children are in the chunk's `VoxelPool`.
+    pub children: [Option<VoxelIndex>; 8],
+    /// Set when the voxel or something below it has been edited since the averaged colors were last calculated.
+    /// Solid voxels have no averaged color, so this only matters for voxels with children.
+    dirty: bool,
 }
 
 /// # NOTE!
@@ -35,7 +62,8 @@ pub struct Voxel {
 pub struct Chunk {
     pub position: Vector2<i128>,
     depth: u32,
-    pub start_voxel: Voxel,
+    voxels: VoxelPool,
+    materials: MaterialTable,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -44,10 +72,9 @@ struct ColorWeight {
     weight: f32,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 struct ParallellVoxelData {
-    pub handle: JoinHandle<Option<Box<Voxel>>>,
+    pub handle: JoinHandle<(VoxelPool, bool)>,
     pub index: usize,
 }
 
@@ -58,8 +85,8 @@ pub struct VoxelData {
     pub pos_xy: Vector2<f32>,
     // The range value is the pos_zw.y value this is done to save space
     pub pos_zw: Vector2<f32>,
-    pub color_rg: Vector2<f32>,
-    pub color_ba: Vector2<f32>,
+    /// The averaged color as 8 bit RGBA like in `CompactVoxelData`, 0 for solid voxels since they get their color from their material.
+    pub color: u32,
     pub _0_0_index: u32,
     pub _0_1_index: u32,
     pub _0_2_index: u32,
@@ -68,194 +95,482 @@ pub struct VoxelData {
     pub _1_1_index: u32,
     pub _1_2_index: u32,
     pub _1_3_index: u32,
+    /// The index in the material buffer, or `NO_MATERIAL` for voxels that are drawn with their averaged color.
+    pub material_index: u32,
 }
 
-impl Voxel {
-    /// # Panics
-    /// This function panics if the weight(volume) of a node somehow becomes negative
-    fn recursive_color_calculator(&mut self, thread_pool: Arc<RwLock<ThreadPoolHelper>>) -> ColorWeight {
-        //TODO: Parallellize this function. Can be done in a similar way as the filling function.
-        let mut actual_children: Vec<Voxel> = vec![];
-        for child in self.children.clone() {
-            if !child.is_none() {
-                actual_children.push(*child.unwrap())
-            }
-        }
+/// Decides which voxels are sent to the GPU by `Chunk::get_oct_tree` and the other functions building oct tree data.
+struct Extraction {
+    camera_pos: Vector3<f64>,
+    pixel_rad: f32,
+    max_error: f32,
+    /// The children that are kept for every voxel with a child in the frustum, if the extraction is frustum culled.
+    /// Voxels that are not in here have nothing in the frustum and are not sent at all.
+    visible: Option<HashMap<VoxelIndex, u8>>,
+    /// The number of voxels that would have been sent without the frustum culling.
+    culled: usize,
+}
 
-        // let length = vec2_one_d_lenght(self.range);
+/// Checks if the voxel at `pos` with the size `range` is completely inside of `fill_range`.
+fn in_fill_range(pos: Vector3<f32>, range: f32, fill_range: Vector3<Vector2<u32>>) -> bool {
+    vec2_one_d_in_range(Vector2::new(pos.x, pos.x + range), Vector2::new(fill_range.x.x as f32, fill_range.x.y as f32))
+    && vec2_one_d_in_range(Vector2::new(pos.y, pos.y + range), Vector2::new(fill_range.y.x as f32, fill_range.y.y as f32))
+    && vec2_one_d_in_range(Vector2::new(pos.z , pos.z + range), Vector2::new(fill_range.z.x as f32, fill_range.z.y as f32))
+}
 
+impl Voxel {
+    fn color_weight(&self, materials: &MaterialTable) -> ColorWeight {
         let x_length = vec2_one_d_lenght(Vector2::new(self.pos.x, self.pos.x + self.range));
         let y_length = vec2_one_d_lenght(Vector2::new(self.pos.y, self.pos.y + self.range));
         let z_length = vec2_one_d_lenght(Vector2::new(self.pos.z, self.pos.z + self.range));
+        ColorWeight {color: self.color(materials), weight: x_length * y_length * z_length}
+    }
+
+    fn new(pos: Vector3<f32>, range: f32) -> Voxel {
+        Voxel { pos, range, averaged_color: Vector4::new(0.0, 0.0, 0.0, 0.0), material: None, children: Default::default(), dirty: true }
+    }
 
-        if actual_children.len() == 0 {
-            return ColorWeight {color: self.color, weight: x_length * y_length * z_length}
-        }
-
-        let mut color_weights: Vec<ColorWeight> = vec![];
-        let mut cpy: Vec<ColorWeight> = vec![];
-        //let mut join_handles = vec![];
-
-        let empty_weight = (x_length/2.0) * (y_length/2.0) * (z_length/2.0);
-
-        for i in 0..self.children.len() {
-            //TODO: Fix the multithreading
-            // if thread_pool.clone().write().unwrap().try_starting_thread() {
-            //     //thread_pool.clone().read().unwrap().print_num_utilized();
-            //     let thread_pool_cpy = thread_pool.clone();
-            //     let mut child = self.children[i].clone();
-            //     join_handles.push(thread::spawn(move || {
-            //         let mut cw = ColorWeight {color: Vector4::new(0.0, 0.0, 0.0, 0.0), weight: empty_weight};
-            //         if child.is_some() {
-            //             cw = child.as_deref_mut().unwrap().recursive_color_calculator(thread_pool_cpy.clone());
-            //         }
-            //         thread_pool_cpy.read().unwrap().end_thread();
-            //         cw
-            //     }));
-            //     continue;
-            // }
-
-            let mut cw = ColorWeight {color: Vector4::new(0.0, 0.0, 0.0, 0.0), weight: empty_weight};
-            if self.children[i].is_some() {
-                cw = self.children[i].as_deref_mut().unwrap().recursive_color_calculator(thread_pool.clone());
+    /// The color the voxel is drawn with, the color of its material in `materials` if it is solid and its averaged color otherwise.
+    pub fn color(&self, materials: &MaterialTable) -> Vector4<f32> {
+        self.material.map_or(self.averaged_color, |material| materials.get(material).color())
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children.iter().all(|child| child.is_none())
+    }
+
+    /// Checks if the voxel has a material. Voxels with children never do, and the start voxel of an empty chunk is the only voxel
+    /// without children that does not.
+    pub fn is_solid(&self) -> bool {
+        self.material.is_some()
+    }
+
+    /// Checks if the voxel covers at most `max_error` pixels on the screen, so that drawing it instead of its children is close enough.
+    /// `pixel_rad` is the angle a pixel covers. The distance is measured to the closest point of the voxel, so a voxel the camera is inside of always needs its children.
+    fn within_lod_error(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32) -> bool {
+        self.range <= max_error * view_cm_size(pixel_rad, distance_to_box(camera_pos, self.pos, self.range))
+    }
+}
+
+impl VoxelData {
+    pub fn pos(&self) -> Vector3<f32> {
+        Vector3::new(self.pos_xy.x, self.pos_xy.y, self.pos_zw.x)
+    }
+
+    pub fn range(&self) -> f32 {
+        self.pos_zw.y
+    }
+
+    /// The averaged color, unpacked from `color`.
+    pub fn color(&self) -> Vector4<f32> {
+        unpack_color(self.color)
+    }
+
+    /// The indices of the children in the oct tree data, `u32::MAX` for the children that are not there.
+    pub fn children(&self) -> [u32; 8] {
+        [self._0_0_index, self._0_1_index, self._0_2_index, self._0_3_index, self._1_0_index, self._1_1_index, self._1_2_index, self._1_3_index]
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.children().iter().all(|child| *child == u32::MAX)
+    }
+}
+
+impl Extraction {
+    fn new(voxels: &VoxelPool, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32, frustum: Option<&Frustum>) -> Extraction {
+        let mut extraction = Extraction { camera_pos, pixel_rad, max_error, visible: None, culled: 0 };
+        if let Some(frustum) = frustum {
+            let mut visible = HashMap::new();
+            extraction.culled = voxels.traverse_and_cull(START_VOXEL, &extraction, frustum, &mut visible);
+            extraction.visible = Some(visible);
+        }
+        extraction
+    }
+
+    /// Checks if the voxel at `index` is sent with its averaged color instead of its children. The start voxel always gets its children,
+    /// so that it is only sent without children if the chunk is empty or one solid voxel.
+    fn cuts_off(&self, voxels: &VoxelPool, index: VoxelIndex) -> bool {
+        index != START_VOXEL && voxels.get(index).within_lod_error(self.camera_pos, self.pixel_rad, self.max_error)
+    }
+
+    /// The children of the voxel at `index` that are sent to the GPU.
+    fn children(&self, voxels: &VoxelPool, index: VoxelIndex) -> [Option<VoxelIndex>; 8] {
+        if self.cuts_off(voxels, index) {
+            return [None; 8];
+        }
+        let mut children = voxels.get(index).children;
+        if let Some(visible) = &self.visible {
+            let mask = visible.get(&index).copied().unwrap_or(0);
+            for (i, child) in children.iter_mut().enumerate() {
+                if mask & (1 << i) == 0 {
+                    *child = None;
+                }
             }
+        }
+        children
+    }
+}
 
-            color_weights.push(cw);
-            cpy.push(cw);
+impl VoxelPool {
+    /// Calculates the averaged colors of the dirty voxels below `index`, where every child counts as much as its volume and missing children count as empty.
+    /// Solid voxels count with the color of their material in `materials`.
+    /// Voxels that are not dirty keep their colors, since nothing below them has changed. The new colors are added to `changes` instead of being set,
+    /// so that different subtrees can be calculated on different threads. Returns the color of the voxel at `index`.
+    /// # Panics
+    /// This function panics if the weight(volume) of a node somehow becomes negative
+    fn recursive_color_calculator(&self, thread_pool: ThreadPool, materials: &MaterialTable, index: VoxelIndex, current_depth: u32, changes: &mut Vec<(VoxelIndex, Vector4<f32>)>) -> Vector4<f32> {
+        let voxel = self.get(index);
+        // Solid voxels have no averaged color to calculate, and the start voxel of an empty chunk is the only voxel without children that is not solid
+        if voxel.is_leaf() || !voxel.dirty {
+            return voxel.color(materials);
         }
 
-        // for data in join_handles {
-        //     let cw = data.join().unwrap();
-        //     color_weights.push(cw);
-        //     cpy.push(cw);
-        // }
+        let ColorWeight {weight: volume, ..} = voxel.color_weight(materials);
+        let empty_weight = volume/8.0;
 
+        let mut color_weights = [ColorWeight {color: Vector4::new(0.0, 0.0, 0.0, 0.0), weight: empty_weight}; 8];
+        if thread_pool.num_threads() == 0 || current_depth >= PARALLEL_DEPTH {
+            for (i, child) in voxel.children.iter().enumerate() {
+                if let Some(child) = *child {
+                    let color = self.recursive_color_calculator(thread_pool.clone(), materials, child, current_depth + 1, changes);
+                    color_weights[i] = ColorWeight {color, weight: self.get(child).color_weight(materials).weight};
+                }
+            }
+        } else {
+            thread_pool.scope(|scope| {
+                let mut join_handles = vec![];
+                for (i, child) in voxel.children.iter().enumerate() {
+                    // Only the subtrees that have changed are worth a job
+                    match *child {
+                        Some(child) if self.get(child).dirty && !self.get(child).is_leaf() => {
+                            let thread_pool_cpy = thread_pool.clone();
+                            join_handles.push((i, child, scope.spawn(move || {
+                                let mut thread_changes = vec![];
+                                let color = self.recursive_color_calculator(thread_pool_cpy, materials, child, current_depth + 1, &mut thread_changes);
+                                (color, thread_changes)
+                            })));
+                        },
+                        Some(child) => color_weights[i] = self.get(child).color_weight(materials),
+                        None => (),
+                    }
+                }
+
+                for (i, child, handle) in join_handles {
+                    let (color, mut thread_changes) = handle.join().unwrap();
+                    changes.append(&mut thread_changes);
+                    color_weights[i] = ColorWeight {color, weight: self.get(child).color_weight(materials).weight};
+                }
+            });
+        }
+
+        // The children are always added up in the same order, so the result is the same no matter which threads calculated them
         let mut total_weight: f32 = 0.0;
-        for color_weight in color_weights {
+        for color_weight in color_weights.iter() {
             if color_weight.weight < 0.0 {
                 panic!("Voxel::recursive_color_calculator(): TheColorWeight should never be less than zero!");
             }
             total_weight += color_weight.weight;
         }
 
-        self.color = Vector4::new(0.0, 0.0, 0.0, 0.0);
-        for color_weight in cpy {
+        let mut color = Vector4::new(0.0, 0.0, 0.0, 0.0);
+        for color_weight in color_weights {
             let percent: f32 = color_weight.weight/total_weight;
-            self.color = self.color + mul_vector4(color_weight.color, Vector4::new(percent, percent, percent, percent));
+            color = color + mul_vector4(color_weight.color, Vector4::new(percent, percent, percent, percent));
         }
-
-        ColorWeight {color: self.color, weight: x_length * y_length * z_length}
+        changes.push((index, color));
+        color
     }
 
-    fn traverse_and_color(&mut self, thread_pool: Arc<RwLock<ThreadPoolHelper>>, depth: u32, current_depth: u32, fill_range: Vector3<Vector2<u32>>, color: Vector4<f32>) {
-        if depth == current_depth {
-            self.color = color;
-            return;
+    fn mark_all_dirty(&mut self, index: VoxelIndex) {
+        let voxel = self.get_mut(index);
+        voxel.dirty = true;
+        for child in voxel.children.into_iter().flatten() {
+            self.mark_all_dirty(child);
         }
+    }
 
-        if vec2_one_d_in_range(Vector2::new(self.pos.x, self.pos.x + self.range), Vector2::new(fill_range.x.x as f32, fill_range.x.y as f32))
-           && vec2_one_d_in_range(Vector2::new(self.pos.y, self.pos.y + self.range), Vector2::new(fill_range.y.x as f32, fill_range.y.y as f32))
-           && vec2_one_d_in_range(Vector2::new(self.pos.z , self.pos.z + self.range), Vector2::new(fill_range.z.x as f32, fill_range.z.y as f32))
-        {
-            self.color = color;
-            return;
+    /// Sets the voxels overlapping `fill_range` to the material, or removes them if `fill` is `None`.
+    /// Returns true if this voxel ended up empty, so that the parent can prune it.
+    ///
+    /// Above `PARALLEL_DEPTH`, when the range goes through more than one child, the new children are handed to the thread pool.
+    /// A child handed to the thread pool is moved into a pool of its own while a job works on it, and moved back afterwards.
+    /// Moving costs as much as the child has voxels, so children that already had voxels below them are handled here instead.
+    #[allow(clippy::too_many_arguments)]
+    fn traverse_and_color(&mut self, thread_pool: ThreadPool, index: VoxelIndex, depth: u32, current_depth: u32, fill_range: Vector3<Vector2<u32>>, fill: Option<MaterialIndex>) -> bool {
+        let voxel = self.get_mut(index);
+        // Everything on the way down to the edited voxels needs new averaged colors
+        voxel.dirty = true;
+        let (pos, range) = (voxel.pos, voxel.range);
+        // The start voxel is only replaced as a whole in a chunk with a depth of 0, deeper chunks are edited through its children so that it never has to be split up
+        if depth == current_depth || (current_depth > 0 && in_fill_range(pos, range, fill_range)) {
+            voxel.averaged_color = Vector4::new(0.0, 0.0, 0.0, 0.0);
+            voxel.material = fill;
+            let children = std::mem::take(&mut voxel.children);
+            for child in children.into_iter().flatten() {
+                self.remove(child);
+            }
+            // The start voxel is never removed, without a material it is an empty chunk
+            return fill.is_none() && current_depth > 0;
         }
 
-        let size = (CHUNKSIZE/(2 as u32).pow(current_depth)) as f32;
-
-        //let mut join_handles: Vec<ParallellVoxelData> = vec![];
+        let size = range/2.0;
 
-        for x in 0..self.children.len()/4 {
-            for y in 0..self.children.len()/4 {
-                for z in 0..self.children.len()/4 {
+        let mut overlapping_children = Vec::with_capacity(8);
+        for x in 0..2 {
+            for y in 0..2 {
+                for z in 0..2 {
 
                     let i = x + z*2 + y*4;
-                    let new_range = size as f32;
-                    let new_pos = Vector3::new(self.pos.x + (x as f32 * size), self.pos.y + (y as f32 * size), self.pos.z + (z as f32 * size));
-                    // let new_x_u_range = Vector2::new((self.x_range.x + (x as f32 * size)) as u32, (self.x_range.x + ((x as f32 + 1.0) * size)) as u32);
-                    // let new_y_u_range = Vector2::new((self.y_range.x + (y as f32 * size)) as u32, (self.y_range.x + ((y as f32 + 1.0) * size)) as u32);
-                    // let new_z_u_range = Vector2::new((self.z_range.x + (z as f32 * size)) as u32, (self.z_range.x + ((z as f32 + 1.0) * size)) as u32);
+                    let new_range = size;
+                    let new_pos = Vector3::new(pos.x + (x as f32 * size), pos.y + (y as f32 * size), pos.z + (z as f32 * size));
 
                     if vec2_one_d_overlapping(fill_range.x, Vector2::new((new_pos.x) as u32, (new_pos.x + new_range) as u32))
                        && vec2_one_d_overlapping(fill_range.y, Vector2::new((new_pos.y) as u32, (new_pos.y + new_range) as u32))
                        && vec2_one_d_overlapping(fill_range.z, Vector2::new((new_pos.z) as u32, (new_pos.z + new_range) as u32)) 
                     {
-                        if self.children[i].is_none() {
-                            self.children[i] = Some(Box::new(Voxel { // x_range: Vector2::new(new_x_u_range.x as f32, new_x_u_range.y as f32),
-                                                                    //  y_range: Vector2::new(new_y_u_range.x as f32, new_y_u_range.y as f32),
-                                                                    //  z_range: Vector2::new(new_z_u_range.x as f32, new_z_u_range.y as f32),
-                                                                     pos: new_pos,
-                                                                     range: new_range,
-                                                                     color: Vector4::new(0.0, 0.0, 0.0, 0.0),
-                                                                     children: Default::default() }));
-                        }
-                        //TODO: Fix the multithreading
-                        // if thread_pool.clone().write().unwrap().try_starting_thread() {
-                        //     let thread_pool_clone = thread_pool.clone();
-                        //     let mut child = self.children[i].clone();
-                        //     join_handles.push(ParallellVoxelData { 
-                        //         handle: thread::spawn(move || {
-                        //             child.as_deref_mut().unwrap().traverse_and_color(thread_pool_clone.clone(), depth, current_depth+1, fill_range, color);
-                        //             thread_pool_clone.clone().read().unwrap().end_thread();
-                        //             child
-                        //             }),
-                        //         index: i });
-                        //     continue;
-                        // }
-                        self.children[i].as_deref_mut().unwrap().traverse_and_color(thread_pool.clone(), depth, current_depth+1, fill_range, color);
+                        overlapping_children.push((i, new_pos));
                     }
                 }
             }
         }
+        // Children at the chunk depth or completely inside of the range are set right away, so they are not worth a thread
+        let partly_changed = |new_pos: Vector3<f32>| current_depth + 1 < depth && !in_fill_range(new_pos, size, fill_range);
+        let use_threads = thread_pool.num_threads() > 0
+                          && current_depth < PARALLEL_DEPTH
+                          && overlapping_children.iter().filter(|(_, new_pos)| partly_changed(*new_pos)).count() > 1;
+
+        let mut join_handles: Vec<ParallellVoxelData> = vec![];
+
+        for (i, new_pos) in overlapping_children {
+            let new_range = size;
+            let (child, new) = match self.get(index).children[i] {
+                None if fill.is_none() => continue,
+                None => {
+                    let child = self.insert(Voxel::new(new_pos, new_range));
+                    self.get_mut(index).children[i] = Some(child);
+                    (child, true)
+                },
+                // A solid voxel above the chunk depth that is only partly changed has to be split up first, so that the rest of it is kept
+                Some(child) if self.get(child).is_leaf() && partly_changed(new_pos) => {
+                    self.subdivide(child);
+                    (child, true)
+                },
+                Some(child) => (child, false),
+            };
+
+            if use_threads && new && partly_changed(new_pos) {
+                let thread_pool_clone = thread_pool.clone();
+                let mut subtree = self.take_subtree(child);
+                self.get_mut(index).children[i] = None;
+                join_handles.push(ParallellVoxelData {
+                    handle: thread_pool.spawn(move || {
+                        let empty = subtree.traverse_and_color(thread_pool_clone, START_VOXEL, depth, current_depth+1, fill_range, fill);
+                        (subtree, empty)
+                        }),
+                    index: i });
+                continue;
+            }
+
+            if self.traverse_and_color(thread_pool.clone(), child, depth, current_depth+1, fill_range, fill) {
+                self.remove(child);
+                self.get_mut(index).children[i] = None;
+            }
+        }
+
+        // Moving the subtrees back changes where their voxels are stored, but not the voxels themselves
+        for thread_data in join_handles {
+            let (subtree, empty) = thread_data.handle.join().unwrap();
+            if !empty {
+                let child = self.graft(&subtree);
+                self.get_mut(index).children[thread_data.index] = Some(child);
+            }
+        }
+
+        let voxel = self.get_mut(index);
+        if voxel.is_leaf() {
+            voxel.averaged_color = Vector4::new(0.0, 0.0, 0.0, 0.0);
+            return current_depth > 0;
+        }
+        if current_depth > 0 {
+            self.collapse(index);
+        }
+        false
+    }
+
+    /// Replaces the voxel at `index` with a solid leaf if it has eight children that are solid leaves with the same material, the opposite of `subdivide`.
+    /// Returns true if it did.
+    fn collapse(&mut self, index: VoxelIndex) -> bool {
+        let children = self.get(index).children;
+        let Some(first) = children[0] else {
+            return false;
+        };
+        let material = self.get(first).material;
+        let uniform = children.iter().all(|child| match child {
+            Some(child) => self.get(*child).is_leaf() && self.get(*child).material == material,
+            None => false,
+        });
+        if material.is_none() || !uniform {
+            return false;
+        }
+        for child in children.into_iter().flatten() {
+            self.remove(child);
+        }
+        let voxel = self.get_mut(index);
+        voxel.children = Default::default();
+        voxel.averaged_color = Vector4::new(0.0, 0.0, 0.0, 0.0);
+        voxel.material = material;
+        true
+    }
+
+    /// Collapses the uniform subtrees below `index` bottom up, and removes the voxels that are neither solid nor have children.
+    /// Returns true if the voxel at `index` is empty, so that the parent can remove it.
+    fn traverse_and_optimize(&mut self, index: VoxelIndex, current_depth: u32) -> bool {
+        let children = self.get(index).children;
+        for (i, child) in children.iter().enumerate() {
+            if let Some(child) = *child {
+                if self.traverse_and_optimize(child, current_depth + 1) {
+                    self.remove(child);
+                    self.get_mut(index).children[i] = None;
+                }
+            }
+        }
+        // The start voxel is never collapsed or removed, like in `traverse_and_color`
+        if current_depth == 0 {
+            return false;
+        }
+        let voxel = self.get(index);
+        if voxel.is_leaf() {
+            return voxel.material.is_none();
+        }
+        self.collapse(index);
+        false
+    }
 
-        // for thread_data in join_handles {
-        //     self.children[thread_data.index] = thread_data.handle.join().unwrap();
-        // }
+    /// Replaces a solid leaf with eight children of the same material.
+    fn subdivide(&mut self, index: VoxelIndex) {
+        let Voxel { pos, range, material, .. } = *self.get(index);
+        let size = range/2.0;
+        for i in 0..8 {
+            let (x, y, z) = ((i % 2) as f32, (i / 4) as f32, ((i / 2) % 2) as f32);
+            let child = self.insert(Voxel { material, ..Voxel::new(Vector3::new(pos.x + x * size, pos.y + y * size, pos.z + z * size), size) });
+            self.get_mut(index).children[i] = Some(child);
+        }
+        self.get_mut(index).material = None;
+    }
+
+    /// Walks down towards the voxel at (`x`, `y`, `z`) and returns the voxel at `depth`, or the solid leaf covering it if that comes first.
+    fn traverse_and_find(&self, index: VoxelIndex, x: u32, y: u32, z: u32, depth: u32, current_depth: u32) -> Option<&Voxel> {
+        let voxel = self.get(index);
+        if voxel.is_leaf() {
+            return voxel.is_solid().then_some(voxel);
+        }
+        if depth == current_depth {
+            return Some(voxel);
+        }
+
+        let half = voxel.range/2.0;
+        let i = (x as f32 >= voxel.pos.x + half) as usize
+              + (z as f32 >= voxel.pos.z + half) as usize * 2
+              + (y as f32 >= voxel.pos.y + half) as usize * 4;
+        self.traverse_and_find(voxel.children[i]?, x, y, z, depth, current_depth + 1)
+    }
+
+    fn traverse_and_collect_leaves<'a>(&'a self, index: VoxelIndex, leaves: &mut Vec<&'a Voxel>) {
+        let voxel = self.get(index);
+        if voxel.is_solid() {
+            leaves.push(voxel);
+        }
+        for child in voxel.children.iter().flatten() {
+            self.traverse_and_collect_leaves(*child, leaves);
+        }
     }
 
     #[allow(dead_code)]
-    fn traverse_and_print_voxel(&self, current_depth: u32) {
-        println!("Depth: {}, Voxel{:?}", current_depth, self);
-        for child in self.children.clone() {
-            if child.is_none() {
+    fn traverse_and_print_voxel(&self, index: VoxelIndex, current_depth: u32) {
+        let voxel = self.get(index);
+        println!("Depth: {}, Voxel{:?}", current_depth, voxel);
+        for child in voxel.children.iter().flatten() {
+            self.traverse_and_print_voxel(*child, current_depth + 1);
+        }
+    }
+
+    /// Counts the voxels below and including `index` that `extraction` would send if nothing was culled.
+    fn count_extracted(&self, index: VoxelIndex, extraction: &Extraction) -> usize {
+        if extraction.cuts_off(self, index) {
+            return 1;
+        }
+        1 + self.get(index).children.iter().flatten().map(|child| self.count_extracted(*child, extraction)).sum::<usize>()
+    }
+
+    /// Finds the voxels with something inside of the frustum and the children of theirs that are, and returns how many voxels were culled.
+    /// Voxels with children where all of the children were culled are culled as well, except for the start voxel.
+    fn traverse_and_cull(&self, index: VoxelIndex, extraction: &Extraction, frustum: &Frustum, visible: &mut HashMap<VoxelIndex, u8>) -> usize {
+        let voxel = self.get(index);
+        if extraction.cuts_off(self, index) || voxel.is_leaf() {
+            return 0;
+        }
+        let mut culled = 0;
+        let mut mask = 0_u8;
+        for (i, child) in voxel.children.iter().enumerate() {
+            let Some(child) = *child else {
                 continue;
+            };
+            let child_voxel = self.get(child);
+            if !frustum.intersects_box(child_voxel.pos, child_voxel.range) {
+                culled += self.count_extracted(child, extraction);
+                continue;
+            }
+            culled += self.traverse_and_cull(child, extraction, frustum, visible);
+            if child_voxel.is_leaf() || extraction.cuts_off(self, child) || visible.contains_key(&child) {
+                mask |= 1 << i;
+            } else {
+                culled += 1;
             }
-            child.unwrap().traverse_and_print_voxel(current_depth + 1);
         }
+        if mask != 0 || index == START_VOXEL {
+            visible.insert(index, mask);
+        }
+        culled
     }
 
-    fn traverse_and_append(&self, camera_pos: Vector3<f64>, pixel_rad: f32, current_vec_len: u32) -> Vec<VoxelData> {
+    fn traverse_and_append(&self, index: VoxelIndex, extraction: &Extraction, current_vec_len: u32) -> Vec<VoxelData> {
+        let voxel = self.get(index);
+        let children = extraction.children(self, index);
 
-        if view_cm_size(pixel_rad, distance_between_points(camera_pos, Vector3::new(0_f64, 0_f64, 0_f64 as f64))) >= self.range {
+        if children.iter().all(|child| child.is_none()) {
+            // A start voxel where everything was culled is sent like an empty chunk
+            // Solid voxels have no averaged color, the shader gets their color from the material
+            let color = if voxel.is_leaf() || index != START_VOXEL { voxel.averaged_color } else { Vector4::new(0.0, 0.0, 0.0, 0.0) };
             // return vec![VoxelData {x_range: self.x_range, y_range: self.y_range, z_range: self.z_range, color_rg:Vector2::new(self.color.x, self.color.y), color_ba:Vector2::new(self.color.z, self.color.w), _0_0_index: u32::MAX, _0_1_index: u32::MAX, _0_2_index: u32::MAX, _0_3_index: u32::MAX, _1_0_index: u32::MAX, _1_1_index: u32::MAX, _1_2_index: u32::MAX, _1_3_index: u32::MAX }]
-            return vec![VoxelData {pos_xy: Vector2::new(self.pos.x, self.pos.y), pos_zw: Vector2::new(self.pos.z, self.range), color_rg:Vector2::new(self.color.x, self.color.y), color_ba:Vector2::new(self.color.z, self.color.w), _0_0_index: u32::MAX, _0_1_index: u32::MAX, _0_2_index: u32::MAX, _0_3_index: u32::MAX, _1_0_index: u32::MAX, _1_1_index: u32::MAX, _1_2_index: u32::MAX, _1_3_index: u32::MAX }]
+            return vec![VoxelData {pos_xy: Vector2::new(voxel.pos.x, voxel.pos.y), pos_zw: Vector2::new(voxel.pos.z, voxel.range), color: pack_color(color), _0_0_index: u32::MAX, _0_1_index: u32::MAX, _0_2_index: u32::MAX, _0_3_index: u32::MAX, _1_0_index: u32::MAX, _1_1_index: u32::MAX, _1_2_index: u32::MAX, _1_3_index: u32::MAX, material_index: voxel.material.map_or(NO_MATERIAL, u32::from) }]
         }
         
         let mut voxels: Vec<VoxelData> = vec![];
         let mut index_array: [u32; 8] = [u32::MAX; 8];
         for y in 0..2_usize {
             for x in 0..4_usize {
-                let child = self.children[x+y*4].clone();
-                if child.is_some() {
-                    let mut data = child.unwrap().traverse_and_append(camera_pos, pixel_rad, voxels.len() as u32 + current_vec_len);//child.clone().read().unwrap().unwrap().traverse_and_append(camera_pos, pixel_rad);
+                if let Some(child) = children[x+y*4] {
+                    let mut data = self.traverse_and_append(child, extraction, voxels.len() as u32 + current_vec_len);
                     voxels.append(&mut data);
                     index_array[x+y*4] = voxels.len() as u32 - 1 + current_vec_len;
                 }
             }
         }
 
-        if voxels.len() == 0 {
-            //return vec![VoxelData {x_range: self.x_range, y_range: self.y_range, z_range: self.z_range, color_rg: Vector2::new(self.color.x, self.color.y), color_ba: Vector2::new(self.color.z, self.color.w), _0_0_index: u32::MAX, _0_1_index: u32::MAX, _0_2_index: u32::MAX, _0_3_index: u32::MAX, _1_0_index: u32::MAX, _1_1_index: u32::MAX, _1_2_index: u32::MAX, _1_3_index: u32::MAX }]
-            return vec![VoxelData {pos_xy: Vector2::new(self.pos.x, self.pos.y), pos_zw: Vector2::new(self.pos.z, self.range), color_rg: Vector2::new(self.color.x, self.color.y), color_ba: Vector2::new(self.color.z, self.color.w), _0_0_index: u32::MAX, _0_1_index: u32::MAX, _0_2_index: u32::MAX, _0_3_index: u32::MAX, _1_0_index: u32::MAX, _1_1_index: u32::MAX, _1_2_index: u32::MAX, _1_3_index: u32::MAX }]
-        }
-
         //voxels.append(&mut vec![VoxelData {x_range: self.x_range, y_range: self.y_range, z_range: self.z_range, color_rg: Vector2::new(self.color.x, self.color.y), color_ba:Vector2::new(self.color.z, self.color.w), _0_0_index: index_array[0], _0_1_index: index_array[1], _0_2_index: index_array[2], _0_3_index: index_array[3], _1_0_index: index_array[4], _1_1_index: index_array[5], _1_2_index: index_array[6], _1_3_index: index_array[7] }]);
-        voxels.append(&mut vec![VoxelData {pos_xy: Vector2::new(self.pos.x, self.pos.y), pos_zw: Vector2::new(self.pos.z, self.range), color_rg: Vector2::new(self.color.x, self.color.y), color_ba:Vector2::new(self.color.z, self.color.w), _0_0_index: index_array[0], _0_1_index: index_array[1], _0_2_index: index_array[2], _0_3_index: index_array[3], _1_0_index: index_array[4], _1_1_index: index_array[5], _1_2_index: index_array[6], _1_3_index: index_array[7] }]);
+        voxels.append(&mut vec![VoxelData {pos_xy: Vector2::new(voxel.pos.x, voxel.pos.y), pos_zw: Vector2::new(voxel.pos.z, voxel.range), color: pack_color(voxel.averaged_color), _0_0_index: index_array[0], _0_1_index: index_array[1], _0_2_index: index_array[2], _0_3_index: index_array[3], _1_0_index: index_array[4], _1_1_index: index_array[5], _1_2_index: index_array[6], _1_3_index: index_array[7], material_index: NO_MATERIAL }]);
         voxels
     }
 
 }
 
+/// Chunks are equal when they hold the same voxels, no matter where in their pools the voxels are stored.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position && self.depth == other.depth && self.materials == other.materials && self.voxels.traverse_and_compare(START_VOXEL, &other.voxels, START_VOXEL)
+    }
+}
+
 impl Chunk {
     /// Initializes empty chunk with specified depth.
     /// # Panics
@@ -264,28 +579,327 @@ impl Chunk {
         if depth > CHUNKPOWER*2 {
             panic!("Chunk::new(): A depth higher than the CHUNKPOWER*2 is not supported at this time");
         }
-        Chunk { position: position, depth: depth, start_voxel: Voxel { // x_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
-                                                                    //    y_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
-                                                                    //    z_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
-                                                                       pos: Vector3::new(0_f32, 0_f32, 0_f32),
-                                                                       range: CHUNKSIZE as f32,
-                                                                       color: Vector4::new(0.0, 0.0, 0.0, 0.0),
-                                                                       children: Default::default() }}
+        let mut voxels = VoxelPool::new();
+        voxels.insert(Voxel { // x_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
+                           //    y_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
+                           //    z_range: Vector2::new(0 as f32, CHUNKSIZE as f32),
+                              pos: Vector3::new(0_f32, 0_f32, 0_f32),
+                              range: CHUNKSIZE as f32,
+                              averaged_color: Vector4::new(0.0, 0.0, 0.0, 0.0),
+                              material: None,
+                              children: Default::default(),
+                              dirty: false });
+        Chunk { position: position, depth: depth, voxels, materials: MaterialTable::new() }
+    }
+
+    /// The voxel covering the whole chunk, the root of the tree.
+    pub fn start_voxel(&self) -> &Voxel {
+        self.voxels.get(START_VOXEL)
+    }
+
+    /// The storage of the voxels, used to look up the children of a voxel.
+    pub fn voxels(&self) -> &VoxelPool {
+        &self.voxels
+    }
+
+    /// The materials the solid voxels of the chunk refer to.
+    pub fn materials(&self) -> &MaterialTable {
+        &self.materials
+    }
+
+    /// Adds a material to the chunk's table so that voxels can be filled with it, and returns its index.
+    /// A material that is already in the table keeps its index.
+    /// # Panics
+    /// The function panics if the table is full, see `MaterialTable::add`.
+    pub fn add_material(&mut self, material: Material) -> MaterialIndex {
+        self.materials.add(material)
+    }
+
+    /// Fills the voxels in the specified range with a material from the chunk's table, see `add_material`.
+    /// # Panics
+    /// The function panics if there is no material at `material` in the table.
+    pub fn fill_material(&mut self, thread_pool: ThreadPool, fill_range: Vector3<Vector2<u32>>, material: MaterialIndex) {
+        self.set_material_deferred(thread_pool.clone(), fill_range, Some(material));
+        self.recalculate_colors(thread_pool);
     }
 
     /// Fills the voxels in the specified range. However, the precision just goes as low as the `depth` specified for the chunk. 
-    pub fn fill_voxels(&mut self, thread_pool: Arc<RwLock<ThreadPoolHelper>>, fill_range: Vector3<Vector2<u32>>, color: Vector4<f32>) {
-        self.start_voxel.traverse_and_color(thread_pool.clone(), self.depth, 0, fill_range, color);
-        self.start_voxel.recursive_color_calculator(thread_pool.clone());
+    /// The voxels get the plain material with `color`, see `Material::from_color`.
+    pub fn fill_voxels(&mut self, thread_pool: ThreadPool, fill_range: Vector3<Vector2<u32>>, color: Vector4<f32>) {
+        self.set_voxels(thread_pool, fill_range, Some(color));
+    }
+
+    /// Removes the voxels in the specified range. Parents that end up without children are removed as well.
+    pub fn clear_voxels(&mut self, thread_pool: ThreadPool, clear_range: Vector3<Vector2<u32>>) {
+        self.set_voxels(thread_pool, clear_range, None);
+    }
+
+    /// Fills the voxels in the specified range with `color`, or removes them if `color` is `None`.
+    /// The averaged colors of the parents are recalculated afterwards.
+    pub fn set_voxels(&mut self, thread_pool: ThreadPool, range: Vector3<Vector2<u32>>, color: Option<Vector4<f32>>) {
+        self.set_voxels_deferred(thread_pool.clone(), range, color);
+        self.recalculate_colors(thread_pool);
+    }
+
+    /// Like `set_voxels`, but without recalculating the averaged colors. Call `recalculate_colors` after the last edit.
+    pub(crate) fn set_voxels_deferred(&mut self, thread_pool: ThreadPool, range: Vector3<Vector2<u32>>, color: Option<Vector4<f32>>) {
+        let material = color.map(|color| self.materials.add_color(color));
+        self.set_material_deferred(thread_pool, range, material);
+    }
+
+    fn set_material_deferred(&mut self, thread_pool: ThreadPool, range: Vector3<Vector2<u32>>, material: Option<MaterialIndex>) {
+        self.voxels.traverse_and_color(thread_pool, START_VOXEL, self.depth, 0, range, material);
+    }
+
+    /// Applies all the edits in order, and recalculates the averaged colors once at the end instead of after every edit.
+    /// Every edit is a range and the color to fill it with, or `None` to remove the voxels, like `set_voxels` takes.
+    /// The voxels are laid out again afterwards, so that the tree is as fast to walk as a freshly loaded one.
+    pub fn set_voxels_batch(&mut self, thread_pool: ThreadPool, edits: impl IntoIterator<Item = (Vector3<Vector2<u32>>, Option<Vector4<f32>>)>) {
+        for (range, color) in edits {
+            self.set_voxels_deferred(thread_pool.clone(), range, color);
+        }
+        self.recalculate_colors(thread_pool);
+        self.voxels.defragment();
+    }
+
+    /// Collapses every voxel where all eight children are solid with the same material into one solid voxel, and removes voxels
+    /// that are neither solid nor have any children. Edits already do this for the voxels they touch, so this is only needed for
+    /// chunks that were built some other way, like chunks saved before edits did it.
+    /// The averaged colors do not change, and the voxels are laid out again like after `set_voxels_batch`. Returns how many voxels were removed.
+    pub fn optimize(&mut self) -> usize {
+        let len = self.voxels.len();
+        self.voxels.traverse_and_optimize(START_VOXEL, 0);
+        self.voxels.defragment();
+        len - self.voxels.len()
+    }
+
+    /// Recalculates the averaged colors of the voxels that have been edited since the last time.
+    pub(crate) fn recalculate_colors(&mut self, thread_pool: ThreadPool) {
+        let mut changes = vec![];
+        self.voxels.recursive_color_calculator(thread_pool, &self.materials, START_VOXEL, 0, &mut changes);
+        for (index, color) in changes {
+            let voxel = self.voxels.get_mut(index);
+            voxel.averaged_color = color;
+            voxel.dirty = false;
+        }
+    }
+
+    /// Recalculates the averaged colors of the whole chunk, not only the parts that have been edited.
+    pub fn recalculate_all_colors(&mut self, thread_pool: ThreadPool) {
+        self.voxels.mark_all_dirty(START_VOXEL);
+        self.recalculate_colors(thread_pool);
+    }
+
+    /// Gets the solid voxel at the given position in the chunk, at the precision of the chunk's `depth`.
+    /// Returns `None` if that position is empty.
+    pub fn get_voxel(&self, x: u32, y: u32, z: u32) -> Option<&Voxel> {
+        self.get_voxel_at_depth(x, y, z, self.depth)
+    }
+
+    /// Gets the voxel containing the given position at `depth`, which for depths above the chunk's `depth` holds the averaged color of its children.
+    /// If a solid voxel covering the position is found before reaching `depth` that voxel is returned instead.
+    /// Returns `None` if the position is empty or outside of the chunk.
+    pub fn get_voxel_at_depth(&self, x: u32, y: u32, z: u32, depth: u32) -> Option<&Voxel> {
+        if x >= CHUNKSIZE || y >= CHUNKSIZE || z >= CHUNKSIZE {
+            return None;
+        }
+        self.voxels.traverse_and_find(START_VOXEL, x, y, z, depth, 0)
+    }
+
+    /// Gets all the solid voxels in the chunk. Solid voxels can be bigger than the cells at the chunk's `depth`, if a fill covered all of them.
+    pub fn get_leaves(&self) -> Vec<&Voxel> {
+        let mut leaves = vec![];
+        self.voxels.traverse_and_collect_leaves(START_VOXEL, &mut leaves);
+        leaves
+    }
+
+    /// The size of the smallest voxels in the chunk, the ones at the chunk's `depth`.
+    pub fn cell_size(&self) -> u32 {
+        CHUNKSIZE/2_u32.pow(self.depth)
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
     }
 
     #[allow(dead_code)]
     pub fn print_chunk(&self) {
-        self.start_voxel.traverse_and_print_voxel(0);
+        self.voxels.traverse_and_print_voxel(START_VOXEL, 0);
     }
 
     /// Get's the oct tree data for this chunk so that it can be used on the GPU
-    pub fn get_oct_tree(&self, camera_pos: Vector3<f64>, pixel_rad: f32) -> Vec<VoxelData> {
-        self.start_voxel.traverse_and_append( camera_pos, pixel_rad, 0)
+    ///
+    /// Voxels that cover at most `max_error` pixels when seen from `camera_pos` are sent without their children, with their averaged color.
+    /// `pixel_rad` is the angle a pixel covers. A `max_error` of 1 keeps every voxel bigger than a pixel, higher values give smaller buffers.
+    /// The start voxel always gets its children, so that it is only sent without children if the chunk is empty or one solid voxel.
+    pub fn get_oct_tree(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32) -> Vec<VoxelData> {
+        let extraction = Extraction::new(&self.voxels, camera_pos, pixel_rad, max_error, None);
+        self.voxels.traverse_and_append(START_VOXEL, &extraction, 0)
+    }
+
+    /// Like `get_oct_tree`, but the voxels with nothing inside of `frustum` are left out. The children that are left out get the same
+    /// index as missing children, so the shader does not need to know about the culling.
+    /// Returns the oct tree data together with the number of voxels that were culled, out of the ones `get_oct_tree` would have sent.
+    pub fn get_oct_tree_culled(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32, frustum: &Frustum) -> (Vec<VoxelData>, usize) {
+        let extraction = Extraction::new(&self.voxels, camera_pos, pixel_rad, max_error, Some(frustum));
+        (self.voxels.traverse_and_append(START_VOXEL, &extraction, 0), extraction.culled)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    type Edit = (Vector3<Vector2<u32>>, Option<Vector4<f32>>);
+
+    fn random_edits(depth: u32, count: usize) -> Vec<Edit> {
+        let mut rng = StdRng::seed_from_u64(4230);
+        let cells = 2_u32.pow(depth);
+        let cell_size = CHUNKSIZE/cells;
+        (0..count).map(|_| {
+            let mut axis = || {
+                let start = rng.gen_range(0..cells);
+                Vector2::new(start, rng.gen_range(start + 1..=cells)) * cell_size
+            };
+            let range = Vector3::new(axis(), axis(), axis());
+            // A third of the edits carve holes, and the colors come from a few so that fills often merge
+            let color = if rng.gen_range(0..3) == 0 { None } else { Some(Vector4::new(rng.gen_range(0..4) as f32 / 3.0, 0.5, 1.0, 1.0)) };
+            (range, color)
+        }).collect()
+    }
+
+    /// Checks that the voxels of both chunks are drawn with the same colors, walking both trees side by side.
+    fn assert_colors_close(a: &Chunk, index_a: VoxelIndex, b: &Chunk, index_b: VoxelIndex) {
+        let (voxel_a, voxel_b) = (a.voxels.get(index_a), b.voxels.get(index_b));
+        let (color_a, color_b) = (voxel_a.color(&a.materials), voxel_b.color(&b.materials));
+        assert!((color_a - color_b).norm() < 1e-5, "the voxel at {:?} is {:?} in one chunk and {:?} in the other", voxel_a.pos, color_a, color_b);
+        for (child_a, child_b) in voxel_a.children.iter().zip(voxel_b.children.iter()) {
+            match (child_a, child_b) {
+                (Some(child_a), Some(child_b)) => assert_colors_close(a, *child_a, b, *child_b),
+                (None, None) => (),
+                _ => panic!("the voxel at {:?} has different children in the two chunks", voxel_a.pos),
+            }
+        }
+    }
+
+    /// Splits the solid voxels below `index` into the cells of the chunk, like in chunks built before edits collapsed them.
+    fn split_into_cells(voxels: &mut VoxelPool, index: VoxelIndex, levels: u32) {
+        if levels == 0 {
+            return;
+        }
+        if voxels.get(index).is_solid() {
+            voxels.subdivide(index);
+        }
+        for child in voxels.get(index).children.into_iter().flatten() {
+            split_into_cells(voxels, child, levels - 1);
+        }
+    }
+
+    #[test]
+    fn optimize_merges_uniform_subtrees() {
+        let thread_pool = ThreadPool::new(Some(0));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 3);
+        let cell = chunk.cell_size();
+        let range = |x: (u32, u32), y: (u32, u32), z: (u32, u32)| Vector3::new(Vector2::new(x.0, x.1) * cell, Vector2::new(y.0, y.1) * cell, Vector2::new(z.0, z.1) * cell);
+        chunk.fill_voxels(thread_pool.clone(), range((0, 4), (0, 4), (0, 4)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        // The other octant next to it has a cell of another material, so it can not become one voxel
+        chunk.fill_voxels(thread_pool.clone(), range((4, 8), (0, 4), (0, 4)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        chunk.fill_voxels(thread_pool, range((5, 6), (1, 2), (2, 3)), Vector4::new(0.9, 0.1, 0.1, 1.0));
+
+        let mut split = chunk.clone();
+        split_into_cells(&mut split.voxels, START_VOXEL, split.depth);
+        assert_eq!(split.get_leaves().len(), 2 * 64);
+        let added = split.voxels.len() - chunk.voxels.len();
+
+        let mut optimized = split.clone();
+        assert_eq!(optimized.optimize(), added);
+        assert!(optimized == chunk);
+        assert_eq!(optimized.get_leaves().len(), 1 + 7 + 8);
+        for (x, y, z) in (0..8).flat_map(|x| (0..8).flat_map(move |y| (0..8).map(move |z| (x * cell, y * cell, z * cell)))) {
+            assert_eq!(optimized.get_voxel(x, y, z).map(|voxel| voxel.material), split.get_voxel(x, y, z).map(|voxel| voxel.material));
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parallel_edits_match_serial_ones() {
+        let depth = 6;
+        let edits = random_edits(depth, 40);
+        let edit_with = |threads| {
+            let thread_pool = ThreadPool::new(Some(threads));
+            let mut one_by_one = Chunk::new(Vector2::new(0, 0), depth);
+            for (range, color) in edits.iter() {
+                one_by_one.set_voxels(thread_pool.clone(), *range, *color);
+            }
+            let mut batched = Chunk::new(Vector2::new(0, 0), depth);
+            batched.set_voxels_batch(thread_pool, edits.iter().copied());
+            (one_by_one, batched)
+        };
+
+        let (serial, serial_batched) = edit_with(1);
+        for threads in [0, 4] {
+            let (one_by_one, batched) = edit_with(threads);
+            // Equal chunks have the same averaged colors down to the last bit
+            assert!(one_by_one == serial, "the edits give a different chunk with {} threads", threads);
+            assert!(batched == serial_batched, "the batched edits give a different chunk with {} threads", threads);
+            // The voxels are laid out from the tree alone after a batch, so they end up at the same indices
+            assert_eq!(batched.voxels.len(), serial_batched.voxels.len());
+            for index in 0..batched.voxels.len() as VoxelIndex {
+                let (voxel, serial_voxel) = (batched.voxels.get(index), serial_batched.voxels.get(index));
+                assert_eq!((voxel.pos, voxel.range, voxel.material, voxel.children), (serial_voxel.pos, serial_voxel.range, serial_voxel.material, serial_voxel.children));
+            }
+        }
+    }
+
+    #[test]
+    fn solid_start_voxel() {
+        let thread_pool = ThreadPool::new(Some(0));
+        let all = Vector3::new(Vector2::new(0, CHUNKSIZE), Vector2::new(0, CHUNKSIZE), Vector2::new(0, CHUNKSIZE));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 0);
+        // A chunk with a depth of 0 is only the start voxel, which is solid once something is filled
+        chunk.fill_voxels(thread_pool.clone(), Vector3::new(Vector2::new(5, 6), Vector2::new(5, 6), Vector2::new(5, 6)), Vector4::new(1.0, 0.5, 0.0, 1.0));
+        assert!(chunk.start_voxel().is_leaf() && chunk.start_voxel().is_solid());
+        assert_eq!(chunk.get_voxel(CHUNKSIZE - 1, 0, 0).unwrap().range, CHUNKSIZE as f32);
+        assert_eq!(chunk.get_leaves().len(), 1);
+        let hit = chunk.raycast(Vector3::new(-1.0, 1.0, 1.0), Vector3::new(1.0, 0.0, 0.0), f32::MAX).unwrap();
+        assert_eq!((hit.index, hit.t, hit.normal), (START_VOXEL, 1.0, Vector3::new(-1.0, 0.0, 0.0)));
+
+        chunk.clear_voxels(thread_pool, all);
+        assert!(chunk.start_voxel().is_leaf() && !chunk.start_voxel().is_solid());
+        assert!(chunk.get_voxel(0, 0, 0).is_none());
+        assert!(chunk.get_leaves().is_empty());
+        assert!(chunk.raycast(Vector3::new(-1.0, 1.0, 1.0), Vector3::new(1.0, 0.0, 0.0), f32::MAX).is_none());
+    }
+
+    #[test]
+    fn deferred_colors_match_a_full_recalculation() {
+        let thread_pool = ThreadPool::new(Some(4));
+        let depth = 6;
+        let edits = random_edits(depth, 60);
+
+        let mut one_by_one = Chunk::new(Vector2::new(0, 0), depth);
+        for (range, color) in edits.iter() {
+            match color {
+                Some(color) => one_by_one.fill_voxels(thread_pool.clone(), *range, *color),
+                None => one_by_one.clear_voxels(thread_pool.clone(), *range),
+            }
+        }
+        let mut recalculated = one_by_one.clone();
+        recalculated.recalculate_all_colors(thread_pool.clone());
+
+        let mut batched = Chunk::new(Vector2::new(0, 0), depth);
+        batched.set_voxels_batch(thread_pool.clone(), edits.iter().copied());
+
+        let mut deferred = Chunk::new(Vector2::new(0, 0), depth);
+        for (range, color) in edits.iter() {
+            deferred.set_voxels_deferred(thread_pool.clone(), *range, *color);
+        }
+        deferred.recalculate_colors(thread_pool);
+
+        assert!(recalculated.voxels().len() > 1000, "the edits should leave a chunk with more than a few voxels");
+        for chunk in [&one_by_one, &batched, &deferred] {
+            assert!(*chunk == recalculated);
+            assert_colors_close(chunk, START_VOXEL, &recalculated, START_VOXEL);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use bytemuck::{Pod, Zeroable};
+use nalgebra::{Vector3, Vector4};
+
+use super::{frustum::Frustum, material::MaterialIndex, Chunk, Extraction, Voxel, START_VOXEL};
+
+/// The bits of `CompactVoxelData::masks` with a bit set for every child that exists.
+const CHILD_MASK: u32 = 0xff;
+/// Set in `CompactVoxelData::masks` if the node has a material.
+const HAS_MATERIAL: u32 = 1 << 8;
+/// Where the material index starts in `CompactVoxelData::masks`.
+const MATERIAL_SHIFT: u32 = 16;
+
+/// A node of the compact octree layout for the GPU, 12 bytes instead of the 56 of `VoxelData`.
+///
+/// The nodes are stored breadth first with the start voxel at index 0, so the children of a node are next to each other.
+/// A node only stores where its first child is, and child `i` is found by counting the children before it in the mask.
+/// Positions and sizes are not stored, they follow from the path taken from the start voxel, like in the chunk file format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct CompactVoxelData {
+    /// The bits 0-7 are set for the children that exist, bit 8 is set if the node has a material and the bits 16-31 are the material index.
+    pub masks: u32,
+    /// The index of the first child. Nodes without children have the index their first child would have had.
+    pub first_child: u32,
+    /// The averaged color as 8 bit RGBA, with red in the lowest byte. Solid nodes get their color from their material, so this is 0 for them.
+    pub color: u32,
+}
+
+// Not derived, since the derive macros leave dead code warnings behind. Three u32s have no padding and every bit pattern is a valid node.
+unsafe impl Zeroable for CompactVoxelData {}
+unsafe impl Pod for CompactVoxelData {}
+
+/// Packs a color into 8 bits per channel, the same way `unpackUnorm4x8` in the shader unpacks it.
+pub(super) fn pack_color(color: Vector4<f32>) -> u32 {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u32;
+    channel(color.x) | channel(color.y) << 8 | channel(color.z) << 16 | channel(color.w) << 24
+}
+
+/// The opposite of `pack_color`.
+pub(super) fn unpack_color(color: u32) -> Vector4<f32> {
+    let channel = |shift: u32| ((color >> shift) & 0xff) as f32 / 255.0;
+    Vector4::new(channel(0), channel(8), channel(16), channel(24))
+}
+
+impl CompactVoxelData {
+    pub(super) fn new(voxel: &Voxel, child_mask: u8, first_child: u32) -> CompactVoxelData {
+        let material = voxel.material.map_or(0, |material| HAS_MATERIAL | (material as u32) << MATERIAL_SHIFT);
+        CompactVoxelData { masks: child_mask as u32 | material, first_child, color: pack_color(voxel.averaged_color) }
+    }
+
+    pub fn child_mask(&self) -> u8 {
+        (self.masks & CHILD_MASK) as u8
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.child_mask() == 0
+    }
+
+    pub fn material(&self) -> Option<MaterialIndex> {
+        (self.masks & HAS_MATERIAL != 0).then_some((self.masks >> MATERIAL_SHIFT) as MaterialIndex)
+    }
+
+    pub fn color(&self) -> Vector4<f32> {
+        unpack_color(self.color)
+    }
+
+    /// Where child `i` is in the node array, if the node has that child.
+    pub fn child(&self, i: usize) -> Option<u32> {
+        let mask = self.child_mask() as u32;
+        if mask & (1 << i) == 0 {
+            return None;
+        }
+        Some(self.first_child + (mask & ((1 << i) - 1)).count_ones())
+    }
+}
+
+impl Chunk {
+    /// Get's the oct tree data for this chunk in the compact layout, see `CompactVoxelData`.
+    /// The same voxels are cut off by the level of detail as in `get_oct_tree`, but the start voxel is the first node instead of the last.
+    pub fn get_compact_oct_tree(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32) -> Vec<CompactVoxelData> {
+        self.append_compact(&Extraction::new(&self.voxels, camera_pos, pixel_rad, max_error, None))
+    }
+
+    /// Like `get_compact_oct_tree`, but the voxels with nothing inside of `frustum` are left out of the child masks, see `get_oct_tree_culled`.
+    /// Returns the nodes together with the number of voxels that were culled.
+    pub fn get_compact_oct_tree_culled(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32, frustum: &Frustum) -> (Vec<CompactVoxelData>, usize) {
+        let extraction = Extraction::new(&self.voxels, camera_pos, pixel_rad, max_error, Some(frustum));
+        (self.append_compact(&extraction), extraction.culled)
+    }
+
+    fn append_compact(&self, extraction: &Extraction) -> Vec<CompactVoxelData> {
+        // The voxels in the order they get their place in the node array, which is the order they are visited in
+        let mut order = vec![START_VOXEL];
+        let mut nodes = Vec::new();
+        while nodes.len() < order.len() {
+            let index = order[nodes.len()];
+            let voxel = self.voxels.get(index);
+            let first_child = order.len() as u32;
+            let mut child_mask = 0_u8;
+            for (i, child) in extraction.children(&self.voxels, index).iter().enumerate() {
+                if let Some(child) = child {
+                    child_mask |= 1 << i;
+                    order.push(*child);
+                }
+            }
+            nodes.push(CompactVoxelData::new(voxel, child_mask, first_child));
+        }
+        nodes
+    }
+}
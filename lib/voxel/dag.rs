@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use nalgebra::{Vector3, Vector4};
+
+use super::{compact::CompactVoxelData, material::MaterialIndex, pool::{VoxelIndex, VoxelPool}, raycast::{entry_normal, slabs}, Chunk, Extraction, CHUNKSIZE, START_VOXEL};
+
+/// A node before it has a place in the DAG. Nodes that are equal are the same node, no matter where in the chunk they are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct DagNode {
+    masks: u32,
+    color: u32,
+    /// The ids of the children in the list of different nodes, or `u32::MAX` for the children that do not exist.
+    children: [u32; 8],
+}
+
+/// A chunk as a sparse voxel DAG, where subtrees that look the same are only stored once and shared by every parent that has them.
+///
+/// The nodes are stored one after another in a list of u32s, with the start voxel first. A node is the masks and the color
+/// like in `CompactVoxelData`, followed by where each of its children starts in the list, in the order of the child mask.
+/// Positions and sizes are not stored, they follow from the path taken from the start voxel, which is what makes the sharing possible.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoxelDag {
+    words: Vec<u32>,
+    node_count: usize,
+    tree_node_count: usize,
+}
+
+/// A solid voxel found in a `VoxelDag`, or a voxel that was cut off by the level of detail.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DagVoxel {
+    pub pos: Vector3<f32>,
+    pub range: f32,
+    pub material: Option<MaterialIndex>,
+    /// The averaged color of a voxel that was cut off, 0 for solid voxels since they get their color from their material.
+    pub color: Vector4<f32>,
+}
+
+/// The closest voxel without children a ray hit in a `VoxelDag`, like `RayHit` for the tree.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DagHit {
+    pub voxel: DagVoxel,
+    /// The word the node of `voxel` starts at, which is what the compute shader writes to the G-buffer.
+    pub node: u32,
+    /// The distance along the ray to where it enters `voxel`. This is 0 if the ray starts inside of it.
+    pub t: f32,
+    /// The normal of the face the ray entered through, zero if the ray starts inside of the voxel.
+    pub normal: Vector3<f32>,
+}
+
+impl VoxelPool {
+    /// Adds the voxel at `index` and the voxels below it to `nodes`, unless an equal node is already there, and returns the id of the node.
+    fn traverse_and_dedup(&self, index: VoxelIndex, extraction: &Extraction, nodes: &mut Vec<DagNode>, lookup: &mut HashMap<DagNode, u32>, tree_node_count: &mut usize) -> u32 {
+        *tree_node_count += 1;
+        let mut children = [u32::MAX; 8];
+        let mut child_mask = 0_u8;
+        for (i, child) in extraction.children(self, index).iter().enumerate() {
+            if let Some(child) = child {
+                children[i] = self.traverse_and_dedup(*child, extraction, nodes, lookup, tree_node_count);
+                child_mask |= 1 << i;
+            }
+        }
+        let data = CompactVoxelData::new(self.get(index), child_mask, 0);
+        let node = DagNode { masks: data.masks, color: data.color, children };
+        *lookup.entry(node).or_insert_with(|| {
+            nodes.push(node);
+            (nodes.len() - 1) as u32
+        })
+    }
+}
+
+impl VoxelDag {
+    /// Gives every node a place in the list, in the order they are first reached from the start voxel at `root`.
+    fn lay_out(nodes: &[DagNode], root: u32, tree_node_count: usize) -> VoxelDag {
+        let mut starts = vec![u32::MAX; nodes.len()];
+        let mut order = vec![];
+        let mut len = 0;
+        let mut stack = vec![root];
+        while let Some(id) = stack.pop() {
+            if starts[id as usize] != u32::MAX {
+                continue;
+            }
+            let node = &nodes[id as usize];
+            starts[id as usize] = len;
+            len += 2 + node.children.iter().filter(|child| **child != u32::MAX).count() as u32;
+            order.push(id);
+            stack.extend(node.children.iter().rev().filter(|child| **child != u32::MAX));
+        }
+
+        let mut words = Vec::with_capacity(len as usize);
+        for id in order {
+            let node = &nodes[id as usize];
+            words.push(node.masks);
+            words.push(node.color);
+            words.extend(node.children.iter().filter(|child| **child != u32::MAX).map(|child| starts[*child as usize]));
+        }
+        VoxelDag { words, node_count: nodes.len(), tree_node_count }
+    }
+
+    /// The DAG in the layout the shader reads it in.
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+
+    pub fn into_words(self) -> Vec<u32> {
+        self.words
+    }
+
+    /// The number of different nodes, the ones that are stored.
+    pub fn node_count(&self) -> usize {
+        self.node_count
+    }
+
+    /// The number of nodes the chunk had as a tree, with every shared node counted every time it is used.
+    pub fn tree_node_count(&self) -> usize {
+        self.tree_node_count
+    }
+
+    /// Checks if the DAG is of an empty chunk. A start voxel without children is the whole chunk as one solid voxel if it has a material,
+    /// and an empty chunk if not.
+    pub fn is_empty(&self) -> bool {
+        let start = self.node(0);
+        start.is_leaf() && start.material().is_none()
+    }
+
+    /// The node starting at `start`, as `CompactVoxelData` without a first child.
+    fn node(&self, start: u32) -> CompactVoxelData {
+        CompactVoxelData { masks: self.words[start as usize], first_child: 0, color: self.words[start as usize + 1] }
+    }
+
+    /// Where child `i` of the node at `start` starts, if the node has that child.
+    pub fn child(&self, start: u32, i: usize) -> Option<u32> {
+        let mask = self.node(start).child_mask() as u32;
+        if mask & (1 << i) == 0 {
+            return None;
+        }
+        Some(self.words[start as usize + 2 + (mask & ((1 << i) - 1)).count_ones() as usize])
+    }
+
+    /// Walks down the DAG towards (`x`, `y`, `z`) the same way `Chunk::get_voxel` walks down the tree, and returns the voxel without children there.
+    /// Returns `None` if that position is empty or outside of the chunk.
+    pub fn get_voxel(&self, x: u32, y: u32, z: u32) -> Option<DagVoxel> {
+        if x >= CHUNKSIZE || y >= CHUNKSIZE || z >= CHUNKSIZE {
+            return None;
+        }
+        if self.is_empty() {
+            return None;
+        }
+        let (mut start, mut pos, mut range) = (0, Vector3::new(0.0, 0.0, 0.0), CHUNKSIZE as f32);
+        loop {
+            let node = self.node(start);
+            if node.is_leaf() {
+                return Some(DagVoxel { pos, range, material: node.material(), color: node.color() });
+            }
+            range /= 2.0;
+            let (cx, cy, cz) = (x as f32 >= pos.x + range, y as f32 >= pos.y + range, z as f32 >= pos.z + range);
+            let i = cx as usize + cz as usize * 2 + cy as usize * 4;
+            pos += Vector3::new(cx as u32 as f32, cy as u32 as f32, cz as u32 as f32) * range;
+            start = self.child(start, i)?;
+        }
+    }
+}
+
+impl VoxelDag {
+    fn traverse_and_raycast(&self, start: u32, pos: Vector3<f32>, range: f32, origin: Vector3<f32>, inv_ray_dir: Vector3<f32>, max_t: f32) -> Option<(DagVoxel, u32, f32)> {
+        let (t_enter, _) = slabs(pos, range, origin, inv_ray_dir)?;
+        if t_enter > max_t {
+            return None;
+        }
+        let node = self.node(start);
+        if node.is_leaf() {
+            return Some((DagVoxel { pos, range, material: node.material(), color: node.color() }, start, t_enter.max(0.0)));
+        }
+
+        // Children are visited front to back, so the first hit is the closest one
+        let size = range/2.0;
+        let mut children: Vec<(u32, Vector3<f32>, f32)> = (0..8)
+            .filter_map(|i| {
+                let child = self.child(start, i)?;
+                let child_pos = pos + Vector3::new((i % 2) as f32, (i / 4) as f32, ((i / 2) % 2) as f32) * size;
+                slabs(child_pos, size, origin, inv_ray_dir).map(|(t, _)| (child, child_pos, t))
+            })
+            .collect();
+        children.sort_by(|a, b| a.2.total_cmp(&b.2));
+
+        children.into_iter().find_map(|(child, child_pos, _)| self.traverse_and_raycast(child, child_pos, size, origin, inv_ray_dir, max_t))
+    }
+
+    /// Casts a ray through the DAG and returns the first voxel without children it hits within `max_t`, the same way `Chunk::raycast` does for the tree.
+    /// `dir` does not have to be normalized, `t` is measured in multiples of it.
+    pub fn raycast(&self, origin: Vector3<f32>, dir: Vector3<f32>, max_t: f32) -> Option<DagHit> {
+        if self.is_empty() {
+            return None;
+        }
+        let inv_ray_dir = Vector3::new(1.0/dir.x, 1.0/dir.y, 1.0/dir.z);
+        let (voxel, node, t) = self.traverse_and_raycast(0, Vector3::zeros(), CHUNKSIZE as f32, origin, inv_ray_dir, max_t)?;
+        Some(DagHit { voxel, node, t, normal: entry_normal(voxel.pos, voxel.range, origin, dir, inv_ray_dir, t) })
+    }
+}
+
+impl Chunk {
+    /// Get's the oct tree data for this chunk as a sparse voxel DAG, see `VoxelDag`. Subtrees that look the same, at any place in the chunk,
+    /// become one node. The same voxels are cut off by the level of detail as in `get_compact_oct_tree`, a `pixel_rad` of 0 keeps all of them.
+    pub fn get_dag(&self, camera_pos: Vector3<f64>, pixel_rad: f32, max_error: f32) -> VoxelDag {
+        let extraction = Extraction::new(&self.voxels, camera_pos, pixel_rad, max_error, None);
+        let (mut nodes, mut lookup, mut tree_node_count) = (vec![], HashMap::new(), 0);
+        let root = self.voxels.traverse_and_dedup(START_VOXEL, &extraction, &mut nodes, &mut lookup, &mut tree_node_count);
+        VoxelDag::lay_out(&nodes, root, tree_node_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector4};
+
+    use crate::threadpool::ThreadPool;
+    use super::*;
+
+    fn range(x: (u32, u32), y: (u32, u32), z: (u32, u32)) -> Vector3<Vector2<u32>> {
+        Vector3::new(Vector2::new(x.0, x.1), Vector2::new(y.0, y.1), Vector2::new(z.0, z.1))
+    }
+
+    /// A 4 by 4 grid of the same pillar, so that most of the tiles are shared, and a wall through some of them so that not all are.
+    fn tiled_chunk() -> Chunk {
+        let thread_pool = ThreadPool::new(Some(2));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 5);
+        let cell = chunk.cell_size();
+        for (x, z) in (0..4).flat_map(|x| (0..4).map(move |z| (x * 8 * cell, z * 8 * cell))) {
+            chunk.fill_voxels(thread_pool.clone(), range((x + cell, x + 3 * cell), (0, 5 * cell), (z + 2 * cell, z + 4 * cell)), Vector4::new(0.3, 0.5, 0.2, 1.0));
+        }
+        chunk.fill_voxels(thread_pool.clone(), range((0, 32 * cell), (0, 3 * cell), (13 * cell, 14 * cell)), Vector4::new(0.8, 0.1, 0.1, 1.0));
+        chunk.clear_voxels(thread_pool, range((20 * cell, 24 * cell), (cell, 2 * cell), (0, 32 * cell)));
+        chunk
+    }
+
+    #[test]
+    fn solid_start_voxel() {
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 0);
+        assert!(chunk.get_dag(Vector3::zeros(), 0.0, 1.0).is_empty());
+        chunk.fill_voxels(ThreadPool::new(Some(0)), range((0, 1), (0, 1), (0, 1)), Vector4::new(0.3, 0.5, 0.2, 1.0));
+        let dag = chunk.get_dag(Vector3::zeros(), 0.0, 1.0);
+        assert!(!dag.is_empty());
+        assert_eq!(dag.get_voxel(7, 8, 9).map(|voxel| (voxel.range, voxel.material)), Some((CHUNKSIZE as f32, chunk.start_voxel().material)));
+        let hit = dag.raycast(Vector3::new(1.0, -2.0, 1.0), Vector3::new(0.0, 1.0, 0.0), f32::MAX).unwrap();
+        assert_eq!((hit.node, hit.t, hit.normal), (0, 2.0, Vector3::new(0.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    fn repeated_subtrees_are_shared() {
+        let dag = tiled_chunk().get_dag(Vector3::zeros(), 0.0, 1.0);
+        assert!(dag.node_count() < dag.tree_node_count(), "{} nodes for {} in the tree", dag.node_count(), dag.tree_node_count());
+    }
+
+    #[test]
+    fn get_voxel_matches_the_tree() {
+        let chunk = tiled_chunk();
+        let dag = chunk.get_dag(Vector3::zeros(), 0.0, 1.0);
+        let cell = chunk.cell_size();
+        for x in (0..32).map(|x| x * cell) {
+            for y in (0..32).map(|y| y * cell) {
+                for z in (0..32).map(|z| z * cell) {
+                    let expected = chunk.get_voxel(x, y, z).map(|voxel| (voxel.pos, voxel.range, voxel.material));
+                    assert_eq!(dag.get_voxel(x, y, z).map(|voxel| (voxel.pos, voxel.range, voxel.material)), expected, "at {} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn raycast_matches_the_tree() {
+        let chunk = tiled_chunk();
+        let dag = chunk.get_dag(Vector3::zeros(), 0.0, 1.0);
+        let size = CHUNKSIZE as f32;
+        // A fan of rays from outside of the chunk over all of it, and rays in every direction from inside of it
+        let outside = Vector3::new(-0.25, 0.6, -0.3) * size;
+        let targets = (0..16).flat_map(|i| (0..16).map(move |j| Vector3::new(i as f32 + 0.5, 0.0, j as f32 + 0.5) * size/16.0));
+        let inside = Vector3::new(0.45, 0.05, 0.55) * size;
+        let directions = (0..12).flat_map(|i| (0..6).map(move |j| {
+            let (yaw, pitch) = (i as f32 * 0.52 + 0.1, j as f32 * 0.5 - 1.3);
+            Vector3::new(yaw.cos() * pitch.cos(), pitch.sin(), yaw.sin() * pitch.cos())
+        }));
+        let rays = targets.map(|target| (outside, target - outside)).chain(directions.map(|dir| (inside, dir)));
+
+        let mut hits = 0;
+        for (origin, dir) in rays {
+            let (tree_hit, dag_hit) = (chunk.raycast(origin, dir, f32::MAX), dag.raycast(origin, dir, f32::MAX));
+            assert_eq!(tree_hit.is_some(), dag_hit.is_some(), "ray from {:?} along {:?}", origin, dir);
+            let (Some(tree_hit), Some(dag_hit)) = (tree_hit, dag_hit) else { continue };
+            assert_eq!((dag_hit.voxel.pos, dag_hit.voxel.range), (tree_hit.voxel.pos, tree_hit.voxel.range));
+            assert_eq!((dag_hit.t, dag_hit.normal), (tree_hit.t, tree_hit.normal));
+            // The DAG has no cells of its own, but the cell the tree hit has to be in the voxel the DAG hit
+            let cell = dag.get_voxel(tree_hit.cell.x, tree_hit.cell.y, tree_hit.cell.z).unwrap();
+            assert_eq!((cell.pos, cell.range), (dag_hit.voxel.pos, dag_hit.voxel.range));
+            hits += 1;
+        }
+        assert!(hits > 100, "only {} rays hit", hits);
+    }
+}
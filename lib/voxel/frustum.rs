@@ -0,0 +1,54 @@
+use nalgebra::{Matrix4, Vector3};
+
+/// The part of the world a camera can see, as the four planes through the camera position along the sides of the screen.
+/// There is no near or far plane, like for the rays of the compute shader.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum {
+    origin: Vector3<f32>,
+    /// The normals of the side planes, pointing into the frustum.
+    normals: [Vector3<f32>; 4],
+}
+
+impl Frustum {
+    /// Builds the frustum of a camera the same way the compute shader makes its rays, from the vertical `field_of_view` in degrees,
+    /// the aspect ratio of the screen and the camera to world matrix, where the camera position is the last column.
+    /// The frustum is `margin` degrees wider on every side, so that the camera can turn a bit before voxels culled with it would be missed.
+    /// If that makes the frustum 180 degrees or wider in a direction, nothing is culled in that direction.
+    pub fn new(field_of_view: f32, aspect_ratio: f32, camera_to_world: Matrix4<f32>, margin: f32) -> Frustum {
+        let half_height = (field_of_view/2.0).to_radians();
+        let half_width = (aspect_ratio * half_height.tan()).atan();
+        let (half_height, half_width) = (half_height + margin.to_radians(), half_width + margin.to_radians());
+
+        // The shader multiplies the camera space direction with the matrix from the left, and flips the x axis afterwards
+        let to_world = |direction: Vector3<f32>| {
+            let direction = (camera_to_world.transpose() * direction.push(0.0)).xyz();
+            Vector3::new(-direction.x, direction.y, direction.z)
+        };
+        // The camera looks along -z, and a normal of zero is a plane everything is inside of
+        let plane = |normal: Vector3<f32>, half_angle: f32| {
+            if half_angle < std::f32::consts::FRAC_PI_2 { to_world(normal).normalize() } else { Vector3::zeros() }
+        };
+        let (sin_w, cos_w, sin_h, cos_h) = (half_width.sin(), half_width.cos(), half_height.sin(), half_height.cos());
+        let normals = [
+            plane(Vector3::new(cos_w, 0.0, -sin_w), half_width),
+            plane(Vector3::new(-cos_w, 0.0, -sin_w), half_width),
+            plane(Vector3::new(0.0, cos_h, -sin_h), half_height),
+            plane(Vector3::new(0.0, -cos_h, -sin_h), half_height),
+        ];
+
+        Frustum { origin: Vector3::new(camera_to_world[12], camera_to_world[13], camera_to_world[14]), normals }
+    }
+
+    /// Checks if any part of the box at `pos` with the size `range` can be inside of the frustum.
+    /// Some boxes close to the corners of the frustum are kept even though they are outside, but no box inside of it is missed.
+    pub fn intersects_box(&self, pos: Vector3<f32>, range: f32) -> bool {
+        self.normals.iter().all(|normal| {
+            // The corner of the box that is the furthest into the frustum along this plane
+            let corner = pos + Vector3::new(
+                if normal.x > 0.0 { range } else { 0.0 },
+                if normal.y > 0.0 { range } else { 0.0 },
+                if normal.z > 0.0 { range } else { 0.0 });
+            normal.dot(&(corner - self.origin)) >= 0.0
+        })
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use bytemuck::{Pod, Zeroable};
+use nalgebra::{Vector2, Vector3, Vector4};
+
+/// The position of a material in a `MaterialTable`.
+pub type MaterialIndex = u16;
+
+/// The most materials a table can hold, since voxels store the index as a `MaterialIndex`.
+pub const MAX_MATERIALS: usize = MaterialIndex::MAX as usize + 1;
+/// The material index the GPU data uses for voxels without a material, the parents that only have an averaged color.
+pub const NO_MATERIAL: u32 = u32::MAX;
+
+/// How a solid voxel looks. All the values go from 0 to 1, except for `emission` which is how many times brighter than
+/// `base_color` the voxel glows, so 0 means it does not glow at all.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Material {
+    pub base_color: Vector3<f32>,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub emission: f32,
+    pub opacity: f32,
+}
+
+/// The material data for the GPU, one per entry in the `MaterialTable`.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct MaterialData {
+    // Only Vector2s are used here for the same reason as in `VoxelData`
+    pub base_color_rg: Vector2<f32>,
+    // The opacity is the base_color_ba.y value
+    pub base_color_ba: Vector2<f32>,
+    pub roughness_metalness: Vector2<f32>,
+    // The second value is only there to keep the size a multiple of the alignment on the GPU
+    pub emission: Vector2<f32>,
+}
+
+// Not derived for the same reason as for `CompactVoxelData`. The fields are all f32s, so there is no padding and every bit pattern is valid.
+unsafe impl Zeroable for MaterialData {}
+unsafe impl Pod for MaterialData {}
+
+impl Material {
+    /// A plain material that only has a color, the way voxels looked before there were materials.
+    /// The alpha of the color is used as the opacity.
+    pub fn from_color(color: Vector4<f32>) -> Material {
+        Material { base_color: color.xyz(), roughness: 1.0, metalness: 0.0, emission: 0.0, opacity: color.w }
+    }
+
+    /// The base color with the opacity as alpha, which is the color voxels with this material get.
+    pub fn color(&self) -> Vector4<f32> {
+        Vector4::new(self.base_color.x, self.base_color.y, self.base_color.z, self.opacity)
+    }
+
+    fn key(&self) -> [u32; 7] {
+        [self.base_color.x, self.base_color.y, self.base_color.z, self.roughness, self.metalness, self.emission, self.opacity].map(f32::to_bits)
+    }
+
+    fn to_data(self) -> MaterialData {
+        MaterialData {
+            base_color_rg: Vector2::new(self.base_color.x, self.base_color.y),
+            base_color_ba: Vector2::new(self.base_color.z, self.opacity),
+            roughness_metalness: Vector2::new(self.roughness, self.metalness),
+            emission: Vector2::new(self.emission, 0.0),
+        }
+    }
+}
+
+/// The materials used by the voxels of a chunk. Voxels refer to materials by their index, and adding a material that is
+/// already in the table gives the index it already has, so a table built with `add` only holds different materials.
+/// Materials are never removed or changed, so the index of a material stays valid.
+#[derive(Debug, Clone, Default)]
+pub struct MaterialTable {
+    materials: Vec<Material>,
+    lookup: HashMap<[u32; 7], MaterialIndex>,
+}
+
+impl PartialEq for MaterialTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.materials == other.materials
+    }
+}
+
+impl MaterialTable {
+    pub fn new() -> MaterialTable {
+        MaterialTable::default()
+    }
+
+    /// # Panics
+    /// The function panics if there is no material at `index`.
+    pub fn get(&self, index: MaterialIndex) -> &Material {
+        &self.materials[index as usize]
+    }
+
+    /// Adds `material` if it is not in the table yet, and returns its index.
+    /// # Panics
+    /// The function panics if the table already holds `MAX_MATERIALS` other materials.
+    pub fn add(&mut self, material: Material) -> MaterialIndex {
+        if let Some(index) = self.lookup.get(&material.key()) {
+            return *index;
+        }
+        self.push(material)
+    }
+
+    /// Adds `material` at the end of the table even if it is already in there, so that the materials keep the indices they had
+    /// in a saved table. Adding the material again gives the first index it has.
+    /// # Panics
+    /// The function panics if the table already holds `MAX_MATERIALS` materials.
+    pub(super) fn push(&mut self, material: Material) -> MaterialIndex {
+        if self.materials.len() >= MAX_MATERIALS {
+            panic!("MaterialTable::push(): A table can not hold more than {} materials", MAX_MATERIALS);
+        }
+        let index = self.materials.len() as MaterialIndex;
+        self.materials.push(material);
+        self.lookup.entry(material.key()).or_insert(index);
+        index
+    }
+
+    /// The index of the plain material with `color`, see `Material::from_color`, which is added if it is not in the table yet.
+    /// Once the table is full the material with the closest color is used instead, so filling with colors never fails.
+    pub fn add_color(&mut self, color: Vector4<f32>) -> MaterialIndex {
+        let material = Material::from_color(color);
+        if self.materials.len() < MAX_MATERIALS || self.lookup.contains_key(&material.key()) {
+            return self.add(material);
+        }
+        let distance = |other: &Material| (other.color() - color).norm_squared();
+        (0..self.materials.len())
+            .min_by(|a, b| distance(&self.materials[*a]).total_cmp(&distance(&self.materials[*b])))
+            .unwrap() as MaterialIndex
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Material> {
+        self.materials.iter()
+    }
+
+    /// The table in the layout the shader reads it in, where the position of a material is its index.
+    pub fn get_material_data(&self) -> Vec<MaterialData> {
+        self.materials.iter().map(|material| material.to_data()).collect()
+    }
+}
@@ -29,4 +29,10 @@ pub fn distance_between_points(point_1: Vector3<f64>, point_2: Vector3<f64>) ->
 
 pub fn view_cm_size(pixel_rad: f32, distance: f32) -> f32 {
     (pixel_rad/2.0).tan() * distance * 2.0
+}
+
+/// The distance from `point` to the closest point of the box at `pos` with the size `range`, which is 0 if the point is inside of the box.
+pub fn distance_to_box(point: Vector3<f64>, pos: Vector3<f32>, range: f32) -> f32 {
+    let axis = |point: f64, min: f32| (min as f64 - point).max(point - (min + range) as f64).max(0.0);
+    (axis(point.x, pos.x).powi(2) + axis(point.y, pos.y).powi(2) + axis(point.z, pos.z).powi(2)).sqrt() as f32
 }
\ No newline at end of file
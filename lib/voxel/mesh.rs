@@ -0,0 +1,365 @@
+use std::{collections::BTreeMap, fmt, io::{self, BufRead, Write}};
+
+use nalgebra::{Vector3, Vector4};
+
+use super::{pool::VoxelIndex, Chunk, CHUNKPOWER, START_VOXEL};
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(io::Error),
+    /// A line that could not be understood, numbered from 1.
+    Parse { line: usize, message: &'static str },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjError::Io(e) => write!(f, "failed to read the OBJ file: {}", e),
+            ObjError::Parse { line, message } => write!(f, "invalid OBJ file at line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ObjError {
+    fn from(e: io::Error) -> Self {
+        ObjError::Io(e)
+    }
+}
+
+/// A triangle mesh where every face has its own vertices, so that the vertex colors are per face.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mesh {
+    pub positions: Vec<Vector3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub colors: Vec<Vector4<f32>>,
+    /// Counter-clockwise when seen from the outside.
+    pub triangles: Vec<[u32; 3]>,
+}
+
+/// A visible face, as `(u_min, u_max, v_min, v_max, color)` in the two axes of its plane other than `axis`, `(axis + 1) % 3` and `(axis + 2) % 3`.
+type Face = (u32, u32, u32, u32, Vector4<f32>);
+/// The visible faces in one plane of a chunk.
+type Slice = Vec<Face>;
+
+impl Chunk {
+    /// Adds the faces of every voxel at `depth` and every solid voxel above it that are not covered by a neighbour to `slices`,
+    /// grouped by the axis they face along, which way they face and the plane they are in.
+    fn traverse_and_collect_faces(&self, index: VoxelIndex, depth: u32, current_depth: u32, slices: &mut BTreeMap<(usize, bool, u32), Slice>) {
+        let voxel = self.voxels.get(index);
+        if current_depth == depth || voxel.is_leaf() {
+            // A voxel with children at `depth` has something in it, so it counts as solid
+            if !voxel.is_leaf() || voxel.is_solid() {
+                let (pos, size) = (voxel.pos.map(|v| v as u32), voxel.range as u32);
+                for axis in 0..3 {
+                    for positive in [false, true] {
+                        let mut corner = pos;
+                        corner[axis] += positive as u32 * size;
+                        self.add_visible_faces(depth, corner, size, current_depth, axis, positive, voxel.color(&self.materials), slices);
+                    }
+                }
+            }
+            return;
+        }
+        for child in voxel.children.iter().flatten() {
+            self.traverse_and_collect_faces(*child, depth, current_depth + 1, slices);
+        }
+    }
+
+    /// Adds the parts of a face with the size `size` at `level`, facing along `axis`, that are not covered by the voxels next to it.
+    /// `corner` is the corner of the face closest to the origin. The neighbour of the same size is looked up with `get_voxel_at_depth`,
+    /// and if it is only partly there the face is split into four and each quarter is checked on its own.
+    #[allow(clippy::too_many_arguments)]
+    fn add_visible_faces(&self, depth: u32, corner: Vector3<u32>, size: u32, level: u32, axis: usize, positive: bool, color: Vector4<f32>, slices: &mut BTreeMap<(usize, bool, u32), Slice>) {
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut neighbour = corner;
+        // Wrapping below 0 gives a position outside of the chunk, which is empty
+        if !positive {
+            neighbour[axis] = corner[axis].wrapping_sub(size);
+        }
+        match self.get_voxel_at_depth(neighbour.x, neighbour.y, neighbour.z, level) {
+            Some(voxel) if voxel.is_leaf() || level == depth => (),
+            Some(_) => {
+                let half = size/2;
+                for (du, dv) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let mut quarter = corner;
+                    quarter[u_axis] += du * half;
+                    quarter[v_axis] += dv * half;
+                    self.add_visible_faces(depth, quarter, half, level + 1, axis, positive, color, slices);
+                }
+            },
+            None => {
+                let (u, v) = (corner[u_axis], corner[v_axis]);
+                slices.entry((axis, positive, corner[axis])).or_default().push((u, u + size, v, v + size, color));
+            },
+        }
+    }
+}
+
+/// Merges the faces of a slice into as few rectangles with the same color as it can. The slice is cut into strips along v wherever a face
+/// starts or ends, neighbouring faces with the same color in a strip are merged along u, and then equal runs in strips next to each other are merged along v.
+fn merge_faces(mut faces: Slice) -> Slice {
+    let mut edges: Vec<u32> = faces.iter().flat_map(|face| [face.2, face.3]).collect();
+    edges.sort_unstable();
+    edges.dedup();
+    faces.sort_by_key(|face| face.2);
+    let mut faces = faces.into_iter().peekable();
+
+    let mut merged = vec![];
+    // The faces in the current strip by where they start along u, faces in a slice never overlap
+    let mut in_strip: BTreeMap<u32, Face> = BTreeMap::new();
+    // The runs of the last strip by where they start and end along u and their color, as faces that are still open along v
+    let mut open: BTreeMap<(u32, u32, [u32; 4]), Face> = BTreeMap::new();
+    for strip in edges.windows(2) {
+        let v_min = strip[0];
+        in_strip.retain(|_, face| face.3 > v_min);
+        while let Some(face) = faces.next_if(|face| face.2 == v_min) {
+            in_strip.insert(face.0, face);
+        }
+
+        let mut runs: Vec<(u32, u32, Vector4<f32>)> = vec![];
+        for face in in_strip.values() {
+            match runs.last_mut() {
+                Some(run) if run.1 == face.0 && run.2 == face.4 => run.1 = face.1,
+                _ => runs.push((face.0, face.1, face.4)),
+            }
+        }
+
+        let mut next_open = BTreeMap::new();
+        for (u_min, u_max, color) in runs {
+            let key = (u_min, u_max, color.map(f32::to_bits).into());
+            let started = open.remove(&key).map_or(v_min, |face| face.2);
+            next_open.insert(key, (u_min, u_max, started, v_min, color));
+        }
+        for (_, face) in std::mem::replace(&mut open, next_open) {
+            merged.push((face.0, face.1, face.2, v_min, face.4));
+        }
+    }
+    let v_max = edges.last().copied().unwrap_or(0);
+    merged.extend(open.into_values().map(|face| (face.0, face.1, face.2, v_max, face.4)));
+    merged
+}
+
+impl Mesh {
+    /// Creates a mesh of the surface of the chunk, where neighbouring faces with the same color are merged into bigger quads.
+    /// The voxels at `depth` are used, so a `depth` lower than the chunk's gives a coarser mesh where every voxel that has something in it is solid.
+    /// The faces are taken from the solid voxels as they are, so a big solid voxel costs as much as a small one.
+    /// The positions are relative to the chunk, in the same units as `Chunk::fill_voxels` takes.
+    /// # Panics
+    /// The function panics if the `depth` value is higher than `CHUNKPOWER`*2.
+    pub fn from_chunk(chunk: &Chunk, depth: u32) -> Mesh {
+        if depth > CHUNKPOWER*2 {
+            panic!("Mesh::from_chunk(): A depth higher than the CHUNKPOWER*2 is not supported");
+        }
+        let depth = depth.min(chunk.depth);
+
+        let mut slices = BTreeMap::new();
+        chunk.traverse_and_collect_faces(START_VOXEL, depth, 0, &mut slices);
+
+        let mut mesh = Mesh::default();
+        for ((axis, positive, plane), faces) in slices {
+            let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+            for (u_min, u_max, v_min, v_max, color) in merge_faces(faces) {
+                let mut corner = Vector3::zeros();
+                corner[axis] = plane as f32;
+                corner[u_axis] = u_min as f32;
+                corner[v_axis] = v_min as f32;
+                let mut du = Vector3::zeros();
+                du[u_axis] = (u_max - u_min) as f32;
+                let mut dv = Vector3::zeros();
+                dv[v_axis] = (v_max - v_min) as f32;
+                let mut normal = Vector3::zeros();
+                normal[axis] = if positive { 1.0 } else { -1.0 };
+
+                mesh.add_quad([corner, corner + du, corner + du + dv, corner + dv], normal, color, positive);
+            }
+        }
+        mesh
+    }
+
+    /// The corners go counter-clockwise around `normal` if `counter_clockwise` is set, and clockwise if not.
+    fn add_quad(&mut self, corners: [Vector3<f32>; 4], normal: Vector3<f32>, color: Vector4<f32>, counter_clockwise: bool) {
+        let first = self.positions.len() as u32;
+        self.positions.extend_from_slice(&corners);
+        self.normals.extend_from_slice(&[normal; 4]);
+        self.colors.extend_from_slice(&[color; 4]);
+        if counter_clockwise {
+            self.triangles.push([first, first + 1, first + 2]);
+            self.triangles.push([first, first + 2, first + 3]);
+        } else {
+            self.triangles.push([first, first + 2, first + 1]);
+            self.triangles.push([first, first + 3, first + 2]);
+        }
+    }
+
+    /// Reads the vertices and faces of a Wavefront OBJ file, polygons are split into triangles.
+    /// Colors written after the positions are read as vertex colors, vertices without them are white.
+    /// The normals in the file are not used, every vertex gets the average normal of the faces around it instead.
+    pub fn read_obj(reader: impl BufRead) -> Result<Mesh, ObjError> {
+        let mut mesh = Mesh::default();
+        for (line_index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let parse_error = |message| ObjError::Parse { line: line_index + 1, message };
+            let mut parts = line.split_whitespace();
+            match parts.next() {
+                Some("v") => {
+                    let values: Vec<f32> = parts.map(|part| part.parse()).collect::<Result<_, _>>().map_err(|_| parse_error("invalid number in a vertex"))?;
+                    let (pos, color) = match values[..] {
+                        [x, y, z] | [x, y, z, _] => (Vector3::new(x, y, z), Vector4::new(1.0, 1.0, 1.0, 1.0)),
+                        [x, y, z, r, g, b] => (Vector3::new(x, y, z), Vector4::new(r, g, b, 1.0)),
+                        [x, y, z, r, g, b, a] => (Vector3::new(x, y, z), Vector4::new(r, g, b, a)),
+                        _ => return Err(parse_error("a vertex needs 3, 4, 6 or 7 values")),
+                    };
+                    mesh.positions.push(pos);
+                    mesh.colors.push(color);
+                },
+                Some("f") => {
+                    let mut indices = vec![];
+                    for part in parts {
+                        // Only the position index of `v/vt/vn` is used, negative indices count from the end
+                        let index: i64 = part.split('/').next().unwrap().parse().map_err(|_| parse_error("invalid index in a face"))?;
+                        let index = match index {
+                            i if i > 0 => i - 1,
+                            i if i < 0 => mesh.positions.len() as i64 + i,
+                            _ => return Err(parse_error("face indices start at 1")),
+                        };
+                        if index < 0 || index >= mesh.positions.len() as i64 {
+                            return Err(parse_error("a face refers to a vertex that does not exist"));
+                        }
+                        indices.push(index as u32);
+                    }
+                    if indices.len() < 3 {
+                        return Err(parse_error("a face needs at least 3 vertices"));
+                    }
+                    for i in 1..indices.len() - 1 {
+                        mesh.triangles.push([indices[0], indices[i], indices[i + 1]]);
+                    }
+                },
+                _ => (),
+            }
+        }
+
+        mesh.normals = vec![Vector3::zeros(); mesh.positions.len()];
+        for triangle in mesh.triangles.iter() {
+            let [a, b, c] = triangle.map(|i| mesh.positions[i as usize]);
+            // Not normalized, so that bigger faces count more
+            let normal = (b - a).cross(&(c - a));
+            for i in triangle {
+                mesh.normals[*i as usize] += normal;
+            }
+        }
+        for normal in mesh.normals.iter_mut() {
+            *normal = normal.try_normalize(0.0).unwrap_or_else(Vector3::zeros);
+        }
+        Ok(mesh)
+    }
+
+    /// Writes the mesh as a Wavefront OBJ file. The colors are written after the positions, which most tools read as vertex colors.
+    pub fn write_obj(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "# Exported by ArtewaldEngine")?;
+        for (pos, color) in self.positions.iter().zip(self.colors.iter()) {
+            writeln!(writer, "v {} {} {} {} {} {}", pos.x, pos.y, pos.z, color.x, color.y, color.z)?;
+        }
+        for normal in self.normals.iter() {
+            writeln!(writer, "vn {} {} {}", normal.x, normal.y, normal.z)?;
+        }
+        // OBJ indices start at 1
+        for triangle in self.triangles.iter() {
+            let [a, b, c] = triangle.map(|i| i + 1);
+            writeln!(writer, "f {}//{} {}//{} {}//{}", a, a, b, b, c, c)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the mesh as a binary little endian PLY file, with normals and 8 bit RGBA colors per vertex.
+    pub fn write_ply(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "ply\nformat binary_little_endian 1.0\ncomment Exported by ArtewaldEngine")?;
+        writeln!(writer, "element vertex {}", self.positions.len())?;
+        writeln!(writer, "property float x\nproperty float y\nproperty float z")?;
+        writeln!(writer, "property float nx\nproperty float ny\nproperty float nz")?;
+        writeln!(writer, "property uchar red\nproperty uchar green\nproperty uchar blue\nproperty uchar alpha")?;
+        writeln!(writer, "element face {}", self.triangles.len())?;
+        writeln!(writer, "property list uchar uint vertex_indices\nend_header")?;
+
+        let mut data = vec![];
+        for i in 0..self.positions.len() {
+            for value in self.positions[i].iter().chain(self.normals[i].iter()) {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+            data.extend(self.colors[i].iter().map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8));
+        }
+        for triangle in self.triangles.iter() {
+            data.push(3);
+            for index in triangle {
+                data.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        writer.write_all(&data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector2;
+
+    use crate::{threadpool::ThreadPool, voxel::CHUNKSIZE};
+    use super::*;
+
+    fn range(x: (u32, u32), y: (u32, u32), z: (u32, u32)) -> Vector3<Vector2<u32>> {
+        Vector3::new(Vector2::new(x.0, x.1), Vector2::new(y.0, y.1), Vector2::new(z.0, z.1))
+    }
+
+    fn area(mesh: &Mesh) -> f64 {
+        mesh.triangles.iter().map(|triangle| {
+            let [a, b, c] = triangle.map(|i| mesh.positions[i as usize].cast::<f64>());
+            (b - a).cross(&(c - a)).norm() / 2.0
+        }).sum()
+    }
+
+    #[test]
+    fn faces_of_different_sizes_are_merged() {
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 16);
+        // The fill is made of solid voxels of a few different sizes, which still make up one box
+        chunk.fill_voxels(ThreadPool::new(Some(0)), range((5, 10), (0, 5), (3, 15)), Vector4::new(1.0, 0.0, 0.0, 1.0));
+        let mesh = Mesh::from_chunk(&chunk, 16);
+        assert_eq!(mesh.triangles.len(), 12);
+        assert_eq!(area(&mesh), 2.0 * (5.0 * 5.0 + 5.0 * 12.0 + 5.0 * 12.0));
+        for triangle in mesh.triangles.iter() {
+            let [a, b, c] = triangle.map(|i| mesh.positions[i as usize]);
+            assert!(((b - a).cross(&(c - a)).normalize() - mesh.normals[triangle[0] as usize]).norm() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn big_solid_voxels_are_not_split_into_cells() {
+        let thread_pool = ThreadPool::new(Some(0));
+        let half = CHUNKSIZE/2;
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 16);
+        chunk.fill_voxels(thread_pool.clone(), range((0, half), (0, half), (0, half)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        assert_eq!(chunk.get_leaves().len(), 1);
+        // A single cell on top of the big voxel hides a part of its top face
+        chunk.fill_voxels(thread_pool, range((0, 1), (half, half + 1), (0, 1)), Vector4::new(0.9, 0.1, 0.1, 1.0));
+        let mesh = Mesh::from_chunk(&chunk, 16);
+        // The top face becomes two quads around the cell, and the cell shows all of its faces except for the bottom one
+        assert_eq!(mesh.triangles.len(), 2 * (5 + 2 + 5));
+        assert_eq!(area(&mesh), 6.0 * (half as f64).powi(2) + 4.0);
+    }
+
+    #[test]
+    fn solid_start_voxel() {
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 0);
+        assert!(Mesh::from_chunk(&chunk, 0).triangles.is_empty());
+        chunk.fill_voxels(ThreadPool::new(Some(0)), range((0, 1), (0, 1), (0, 1)), Vector4::new(1.0, 1.0, 1.0, 1.0));
+        let mesh = Mesh::from_chunk(&chunk, 0);
+        assert_eq!(mesh.triangles.len(), 12);
+        assert_eq!(area(&mesh), 6.0 * (CHUNKSIZE as f64).powi(2));
+    }
+}
@@ -0,0 +1,139 @@
+use super::{Voxel, START_VOXEL};
+
+/// The position of a voxel in a `VoxelPool`.
+pub type VoxelIndex = u32;
+
+/// The storage of all the voxels in a chunk. The voxels are kept next to each other in one `Vec` and point to their children by index,
+/// so a chunk is only a few allocations and cloning it is a single copy.
+/// The places of removed voxels are reused by the next voxels that are added.
+#[derive(Debug, Clone, Default)]
+pub struct VoxelPool {
+    voxels: Vec<Voxel>,
+    free: Vec<VoxelIndex>,
+}
+
+impl VoxelPool {
+    pub fn new() -> VoxelPool {
+        VoxelPool::default()
+    }
+
+    /// # Panics
+    /// The function panics if there has never been a voxel at `index`.
+    pub fn get(&self, index: VoxelIndex) -> &Voxel {
+        &self.voxels[index as usize]
+    }
+
+    pub(crate) fn get_mut(&mut self, index: VoxelIndex) -> &mut Voxel {
+        &mut self.voxels[index as usize]
+    }
+
+    /// The children of `voxel` that exist, in the order of their child index.
+    pub fn children<'a>(&'a self, voxel: &'a Voxel) -> impl Iterator<Item = &'a Voxel> {
+        voxel.children.iter().flatten().map(|child| self.get(*child))
+    }
+
+    /// Adds a voxel, in the place of a removed one if there is one.
+    pub(crate) fn insert(&mut self, voxel: Voxel) -> VoxelIndex {
+        match self.free.pop() {
+            Some(index) => {
+                self.voxels[index as usize] = voxel;
+                index
+            },
+            None => {
+                self.voxels.push(voxel);
+                (self.voxels.len() - 1) as VoxelIndex
+            },
+        }
+    }
+
+    /// Removes the voxel at `index` and everything below it. The parent still has to forget the index itself.
+    pub(crate) fn remove(&mut self, index: VoxelIndex) {
+        let children = std::mem::take(&mut self.get_mut(index).children);
+        for child in children.into_iter().flatten() {
+            self.remove(child);
+        }
+        self.free.push(index);
+    }
+
+    /// Moves the voxel at `index` and everything below it into a pool of its own, where it is the first voxel.
+    /// The parent still has to forget the index itself.
+    pub(crate) fn take_subtree(&mut self, index: VoxelIndex) -> VoxelPool {
+        let mut subtree = VoxelPool::new();
+        self.traverse_and_move(index, &mut subtree);
+        subtree
+    }
+
+    fn traverse_and_move(&mut self, index: VoxelIndex, subtree: &mut VoxelPool) -> VoxelIndex {
+        let voxel = self.get(index).clone();
+        let children = voxel.children;
+        self.free.push(index);
+        let new_index = subtree.insert(voxel);
+        for (i, child) in children.iter().enumerate() {
+            if let Some(child) = child {
+                let new_child = self.traverse_and_move(*child, subtree);
+                subtree.get_mut(new_index).children[i] = Some(new_child);
+            }
+        }
+        new_index
+    }
+
+    /// Moves the voxels below `START_VOXEL` next to each other in depth first order and drops the places of removed voxels.
+    /// After many edits the children of a voxel can be spread over the whole pool, which makes walking the tree slow.
+    pub(crate) fn defragment(&mut self) {
+        let mut voxels = Vec::with_capacity(self.len());
+        self.traverse_and_copy(START_VOXEL, &mut voxels);
+        *self = VoxelPool { voxels, free: vec![] };
+    }
+
+    fn traverse_and_copy(&self, index: VoxelIndex, voxels: &mut Vec<Voxel>) -> VoxelIndex {
+        let voxel = self.get(index);
+        let new_index = voxels.len();
+        voxels.push(voxel.clone());
+        for (i, child) in voxel.children.iter().enumerate() {
+            if let Some(child) = child {
+                voxels[new_index].children[i] = Some(self.traverse_and_copy(*child, voxels));
+            }
+        }
+        new_index as VoxelIndex
+    }
+
+    /// Moves all the voxels of a pool made by `take_subtree` into this pool, and returns where the first voxel ended up.
+    pub(crate) fn graft(&mut self, subtree: &VoxelPool) -> VoxelIndex {
+        self.traverse_and_graft(subtree, 0)
+    }
+
+    fn traverse_and_graft(&mut self, subtree: &VoxelPool, index: VoxelIndex) -> VoxelIndex {
+        let voxel = subtree.get(index).clone();
+        let children = voxel.children;
+        let new_index = self.insert(voxel);
+        for (i, child) in children.iter().enumerate() {
+            if let Some(child) = child {
+                let new_child = self.traverse_and_graft(subtree, *child);
+                self.get_mut(new_index).children[i] = Some(new_child);
+            }
+        }
+        new_index
+    }
+
+    /// The number of voxels in use.
+    pub fn len(&self) -> usize {
+        self.voxels.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks if the tree below `index` holds the same voxels as the tree below `other_index` in `other`, no matter where in the pools they are stored.
+    pub(crate) fn traverse_and_compare(&self, index: VoxelIndex, other: &VoxelPool, other_index: VoxelIndex) -> bool {
+        let (a, b) = (self.get(index), other.get(other_index));
+        if a.pos != b.pos || a.range != b.range || a.averaged_color != b.averaged_color || a.material != b.material {
+            return false;
+        }
+        a.children.iter().zip(b.children.iter()).all(|children| match children {
+            (Some(a), Some(b)) => self.traverse_and_compare(*a, other, *b),
+            (None, None) => true,
+            _ => false,
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use nalgebra::Vector3;
+
+use super::{material::NO_MATERIAL, pool::{VoxelIndex, VoxelPool}, Chunk, Voxel, VoxelData, START_VOXEL};
+
+/// The closest solid voxel a ray hit.
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit<'a> {
+    /// The leaf that was hit. This can be bigger than the cells at the chunk's `depth` if it is a solid voxel higher up in the tree.
+    pub voxel: &'a Voxel,
+    /// Where `voxel` is in the chunk's `VoxelPool`.
+    pub index: VoxelIndex,
+    /// The distance along the ray to where it enters `voxel`. This is 0 if the ray starts inside of it.
+    pub t: f32,
+    /// The normal of the face the ray entered through, zero if the ray starts inside of the voxel.
+    pub normal: Vector3<f32>,
+    /// The position of the cell at the chunk's `depth` that was hit, in the same units as `Chunk::get_voxel` takes.
+    pub cell: Vector3<u32>,
+}
+
+/// The same slab test as `slabs()` in the compute shader, from https://jcgt.org/published/0007/03/04/.
+/// Returns the distances to where the ray enters and exits the voxel, if it hits it.
+pub fn slabs(pos: Vector3<f32>, range: f32, origin: Vector3<f32>, inv_ray_dir: Vector3<f32>) -> Option<(f32, f32)> {
+    let p0 = pos;
+    let p1 = pos.add_scalar(range);
+
+    let t0 = (p0 - origin).component_mul(&inv_ray_dir);
+    let t1 = (p1 - origin).component_mul(&inv_ray_dir);
+    let tmin = t0.inf(&t1);
+    let tmax = t0.sup(&t1);
+    let tmax_val = tmax.min();
+    let tmin_val = tmin.max();
+    if tmin_val <= tmax_val && tmax_val >= 0.0 {
+        return Some((tmin_val, tmax_val));
+    }
+    None
+}
+
+/// The normal of the face a ray entered the voxel at `pos` with the size `range` through, when it entered it at `t`.
+/// The normal is zero if the ray starts inside of the voxel.
+pub(super) fn entry_normal(pos: Vector3<f32>, range: f32, origin: Vector3<f32>, dir: Vector3<f32>, inv_ray_dir: Vector3<f32>, t: f32) -> Vector3<f32> {
+    if t <= 0.0 {
+        return Vector3::zeros();
+    }
+    // The ray entered through the face on the axis where it entered the slab last
+    let t0 = (pos - origin).component_mul(&inv_ray_dir);
+    let t1 = (pos.add_scalar(range) - origin).component_mul(&inv_ray_dir);
+    let axis = t0.inf(&t1).imax();
+    let mut normal = Vector3::zeros();
+    normal[axis] = -dir[axis].signum();
+    normal
+}
+
+/// The voxel of the oct tree data from `Chunk::get_oct_tree` that a ray hit, see `raycast_oct_tree`.
+#[derive(Debug, Clone, Copy)]
+pub struct OctTreeHit {
+    /// Where the voxel is in the oct tree data.
+    pub index: u32,
+    pub voxel: VoxelData,
+    /// The distance along the ray to where it enters `voxel`. This is 0 if the ray starts inside of it.
+    pub t: f32,
+    /// The normal of the face the ray entered through, zero if the ray starts inside of the voxel.
+    pub normal: Vector3<f32>,
+}
+
+/// Child `i` of a voxel is child `i ^ octant_mask(dir)` when the children are visited front to back, like in the compute shader.
+/// A ray can only go from a child to one with a higher mirrored index, since it moves the same way along every axis the whole time.
+fn octant_mask(dir: Vector3<f32>) -> usize {
+    (dir.x < 0.0) as usize | ((dir.z < 0.0) as usize) << 1 | ((dir.y < 0.0) as usize) << 2
+}
+
+/// Casts a ray through oct tree data from `Chunk::get_oct_tree` the same way `voxel_hit()` in the compute shader does, so that the traversal
+/// of the shader can be checked on the CPU. The start voxel is the last one in the data, and the tree can be of any depth.
+/// The children are visited front to back, so this is the first leaf the ray hits, like for `Chunk::raycast`.
+/// # Panics
+/// The function panics if `oct_tree` is empty.
+pub fn raycast_oct_tree(oct_tree: &[VoxelData], origin: Vector3<f32>, dir: Vector3<f32>) -> Option<OctTreeHit> {
+    let inv_ray_dir = Vector3::new(1.0/dir.x, 1.0/dir.y, 1.0/dir.z);
+    let mirror = octant_mask(dir);
+    let hit = |index: u32, voxel: VoxelData, t_enter: f32| {
+        let t = t_enter.max(0.0);
+        OctTreeHit { index, voxel, t, normal: entry_normal(voxel.pos(), voxel.range(), origin, dir, inv_ray_dir, t) }
+    };
+    let start = oct_tree.len() as u32 - 1;
+    let voxel = oct_tree[start as usize];
+    let (t_enter, _) = slabs(voxel.pos(), voxel.range(), origin, inv_ray_dir)?;
+    // A start voxel without children is the whole chunk as one solid voxel if it has a material, and an empty chunk if not
+    if voxel.is_leaf() {
+        return (voxel.material_index != NO_MATERIAL).then(|| hit(start, voxel, t_enter));
+    }
+
+    // The way down, with the next child to look at for every voxel on it
+    let mut stack = vec![(start, 0_usize)];
+    while let Some((node, next_child)) = stack.last_mut() {
+        if *next_child == 8 {
+            stack.pop();
+            continue;
+        }
+        let child = oct_tree[*node as usize].children()[*next_child ^ mirror];
+        *next_child += 1;
+
+        if child == u32::MAX {
+            continue;
+        }
+        let voxel = oct_tree[child as usize];
+        let Some((t_enter, _)) = slabs(voxel.pos(), voxel.range(), origin, inv_ray_dir) else {
+            continue;
+        };
+        if voxel.is_leaf() {
+            return Some(hit(child, voxel, t_enter));
+        }
+        stack.push((child, 0));
+    }
+    None
+}
+
+impl VoxelPool {
+    fn traverse_and_raycast(&self, index: VoxelIndex, origin: Vector3<f32>, inv_ray_dir: Vector3<f32>, max_t: f32) -> Option<(VoxelIndex, f32)> {
+        let voxel = self.get(index);
+        let (t_enter, _) = slabs(voxel.pos, voxel.range, origin, inv_ray_dir)?;
+        if t_enter > max_t {
+            return None;
+        }
+
+        if voxel.is_leaf() {
+            return voxel.is_solid().then_some((index, t_enter.max(0.0)));
+        }
+
+        // Children are visited front to back, so the first hit is the closest one
+        let mut children: Vec<(VoxelIndex, f32)> = voxel.children.iter()
+            .flatten()
+            .filter_map(|child| {
+                let child_voxel = self.get(*child);
+                slabs(child_voxel.pos, child_voxel.range, origin, inv_ray_dir).map(|(t, _)| (*child, t))
+            })
+            .collect();
+        children.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+        children.into_iter().find_map(|(child, _)| self.traverse_and_raycast(child, origin, inv_ray_dir, max_t))
+    }
+}
+
+impl Chunk {
+    /// Casts a ray through the chunk and returns the first solid voxel it hits within `max_t`.
+    /// `dir` does not have to be normalized, `t` is measured in multiples of it.
+    pub fn raycast(&self, origin: Vector3<f32>, dir: Vector3<f32>, max_t: f32) -> Option<RayHit<'_>> {
+        let inv_ray_dir = Vector3::new(1.0/dir.x, 1.0/dir.y, 1.0/dir.z);
+        let (index, t) = self.voxels.traverse_and_raycast(START_VOXEL, origin, inv_ray_dir, max_t)?;
+        let voxel = self.voxels.get(index);
+
+        let point = origin + dir * t;
+        let normal = entry_normal(voxel.pos, voxel.range, origin, dir, inv_ray_dir, t);
+
+        // Step half a cell into the voxel, so that the hit point is not on the border to the neighbouring cell
+        let cell_size = self.cell_size() as f32;
+        let inside = (point - normal * (cell_size/2.0)).sup(&voxel.pos).inf(&voxel.pos.add_scalar(voxel.range - cell_size/2.0));
+        let cell = inside.map(|v| ((v/cell_size).floor() * cell_size) as u32);
+
+        Some(RayHit { voxel, index, t, normal, cell })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector4};
+
+    use crate::{threadpool::ThreadPool, voxel::CHUNKSIZE};
+    use super::*;
+
+    fn range(x: (u32, u32), y: (u32, u32), z: (u32, u32)) -> Vector3<Vector2<u32>> {
+        Vector3::new(Vector2::new(x.0, x.1), Vector2::new(y.0, y.1), Vector2::new(z.0, z.1))
+    }
+
+    /// Fills that overlap each other, with a whole octant that stays one big solid voxel next to single cells at the chunk's depth.
+    fn test_chunk() -> Chunk {
+        let thread_pool = ThreadPool::new(Some(2));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 6);
+        let cell = chunk.cell_size();
+        chunk.fill_voxels(thread_pool.clone(), range((0, 32 * cell), (0, 32 * cell), (0, 32 * cell)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        chunk.fill_voxels(thread_pool.clone(), range((20 * cell, 45 * cell), (10 * cell, 17 * cell), (25 * cell, 50 * cell)), Vector4::new(0.9, 0.1, 0.1, 1.0));
+        chunk.fill_voxels(thread_pool.clone(), range((40 * cell, 48 * cell), (40 * cell, 48 * cell), (8 * cell, 16 * cell)), Vector4::new(0.1, 0.1, 0.9, 1.0));
+        chunk.clear_voxels(thread_pool.clone(), range((28 * cell, 37 * cell), (12 * cell, 14 * cell), (0, 64 * cell)));
+        for i in 0..8 {
+            let pos = (50 + i) * cell;
+            chunk.fill_voxels(thread_pool.clone(), range((pos, pos + cell), (3 * i * cell, (3 * i + 1) * cell), (60 * cell - pos, 61 * cell - pos)), Vector4::new(1.0, 1.0, 0.0, 1.0));
+        }
+        chunk
+    }
+
+    #[test]
+    fn oct_tree_raycast_of_a_solid_start_voxel() {
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 0);
+        let (origin, dir) = (Vector3::new(1.0, 1.0, -3.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(raycast_oct_tree(&chunk.get_oct_tree(Vector3::zeros(), 0.0, 1.0), origin, dir).is_none());
+        chunk.fill_voxels(ThreadPool::new(Some(0)), range((0, 1), (0, 1), (0, 1)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        let hit = raycast_oct_tree(&chunk.get_oct_tree(Vector3::zeros(), 0.0, 1.0), origin, dir).unwrap();
+        assert_eq!((hit.index, hit.t, hit.normal), (0, 3.0, Vector3::new(0.0, 0.0, -1.0)));
+    }
+
+    #[test]
+    fn oct_tree_raycast_matches_the_chunk() {
+        let chunk = test_chunk();
+        let oct_tree = chunk.get_oct_tree(Vector3::zeros(), 0.0, 1.0);
+        let size = CHUNKSIZE as f32;
+        // Fans of rays from outside of the chunk, from an empty place inside of it and from inside of the big solid voxel
+        let origins = [Vector3::new(-0.3, 1.2, -0.2), Vector3::new(1.1, 0.4, 1.3), Vector3::new(0.55, 0.75, 0.35), Vector3::new(0.21, 0.33, 0.17)];
+        let mut hits = 0;
+        for origin in origins.map(|origin| origin * size) {
+            for (yaw, pitch) in (0..24).flat_map(|i| (0..12).map(move |j| (i as f32 * 0.26 + 0.05, j as f32 * 0.26 - 1.45))) {
+                let dir = Vector3::new(yaw.cos() * pitch.cos(), pitch.sin(), yaw.sin() * pitch.cos());
+                let (expected, hit) = (chunk.raycast(origin, dir, f32::MAX), raycast_oct_tree(&oct_tree, origin, dir));
+                assert_eq!(hit.is_some(), expected.is_some(), "ray from {:?} along {:?}", origin, dir);
+                let (Some(expected), Some(hit)) = (expected, hit) else { continue };
+                assert_eq!((hit.voxel.pos(), hit.voxel.range()), (expected.voxel.pos, expected.voxel.range), "ray from {:?} along {:?}", origin, dir);
+                assert_eq!((hit.t, hit.normal), (expected.t, expected.normal));
+                hits += 1;
+            }
+        }
+        assert!(hits > 300, "only {} rays hit", hits);
+    }
+}
@@ -0,0 +1,232 @@
+use std::io::{self, Write};
+
+use nalgebra::{Matrix4, Vector3, Vector4};
+
+use crate::threadpool::ThreadPool;
+
+use super::{compact::pack_color, material::MaterialTable, raycast::RayHit, Chunk};
+
+/// A camera like the `CameraData` the compute shaders get, with what is needed to make the rays.
+#[derive(Debug, Clone, Copy)]
+pub struct Camera {
+    /// The vertical field of view in degrees.
+    pub field_of_view: u32,
+    pub aspect_ratio: f32,
+    /// The camera to world matrix, where the camera position is the last column.
+    pub camera_to_world: Matrix4<f32>,
+    /// The color of the pixels where the rays do not hit anything.
+    pub clear_color: Vector4<f32>,
+}
+
+/// An image with 8 bit RGBA pixels, stored row by row from the top left.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbaImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// What the rays hit for every pixel of an image, stored row by row from the top left like `RgbaImage`.
+/// This is what the compute shaders write to their G-buffer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GBuffer {
+    pub width: u32,
+    pub height: u32,
+    /// The distance from the camera to the hit, `f32::INFINITY` where nothing was hit.
+    pub depth: Vec<f32>,
+    /// The normal of the face that was hit, zero where nothing was hit or the camera is inside of the voxel.
+    pub normals: Vec<Vector3<f32>>,
+    /// The node that was hit, `u32::MAX` where nothing was hit. The compute shaders give where the node is in the oct tree data they got,
+    /// and `Chunk::render_with_g_buffer` gives where the voxel is in the chunk's `VoxelPool`.
+    pub nodes: Vec<u32>,
+}
+
+impl Camera {
+    pub fn position(&self) -> Vector3<f32> {
+        Vector3::new(self.camera_to_world[12], self.camera_to_world[13], self.camera_to_world[14])
+    }
+
+    /// The direction of the ray through the middle of the pixel at (`x`, `y`) in an image with the size `width` times `height`,
+    /// made the same way as in `main()` of the compute shaders.
+    pub fn ray_direction(&self, x: u32, y: u32, width: u32, height: u32) -> Vector3<f32> {
+        let fov_tan = (self.field_of_view as f32/2.0).to_radians().tan();
+        let (ndc_x, ndc_y) = ((x as f32 + 0.5)/width as f32, (y as f32 + 0.5)/height as f32);
+        let camera_pixel = Vector4::new((2.0 * ndc_x - 1.0) * self.aspect_ratio * fov_tan, (1.0 - 2.0 * ndc_y) * fov_tan, -1.0, 0.0);
+        // GLSL multiplies the vector with the matrix from the left, which is the same as multiplying with the transpose
+        let direction = (self.camera_to_world.transpose() * camera_pixel).xyz().normalize();
+        Vector3::new(-direction.x, direction.y, direction.z)
+    }
+}
+
+impl RgbaImage {
+    pub fn new(width: u32, height: u32) -> RgbaImage {
+        RgbaImage { width, height, pixels: vec![0; (width * height * 4) as usize] }
+    }
+
+    /// # Panics
+    /// The function panics if the pixel is outside of the image.
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let start = ((y * self.width + x) * 4) as usize;
+        self.pixels[start..start + 4].try_into().unwrap()
+    }
+
+    /// Writes the image as a binary PPM file. PPM has no alpha, so it is left out.
+    /// The writer is not buffered here, so wrap it in a `BufWriter` when writing to a file.
+    pub fn write_ppm(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in self.pixels.chunks(4) {
+            writer.write_all(&pixel[..3])?;
+        }
+        Ok(())
+    }
+}
+
+impl GBuffer {
+    pub fn new(width: u32, height: u32) -> GBuffer {
+        let len = (width * height) as usize;
+        GBuffer { width, height, depth: vec![f32::INFINITY; len], normals: vec![Vector3::zeros(); len], nodes: vec![u32::MAX; len] }
+    }
+
+    /// Writes the depth as a grayscale PFM file, which keeps the exact values. PFM stores the rows from the bottom up.
+    /// The writer is not buffered here, so wrap it in a `BufWriter` when writing to a file.
+    pub fn write_depth_pfm(&self, mut writer: impl Write) -> io::Result<()> {
+        // The negative scale means that the values are little endian
+        write!(writer, "Pf\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.depth.chunks(self.width.max(1) as usize).rev() {
+            for depth in row {
+                writer.write_all(&depth.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the normals as a binary PPM file, where every axis from -1 to 1 becomes a channel from 0 to 255.
+    /// The writer is not buffered here, so wrap it in a `BufWriter` when writing to a file.
+    pub fn write_normal_ppm(&self, mut writer: impl Write) -> io::Result<()> {
+        write!(writer, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for normal in &self.normals {
+            writer.write_all(normal.map(|v| ((v * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8).as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the nodes as the width and the height followed by one value for every pixel, all of them as little endian u32s.
+    /// There is no common image format that keeps 32 bit integers, so this is a format of its own.
+    /// The writer is not buffered here, so wrap it in a `BufWriter` when writing to a file.
+    pub fn write_nodes(&self, mut writer: impl Write) -> io::Result<()> {
+        for value in [self.width, self.height].iter().chain(&self.nodes) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// The same shading as `shade_hit()` in the compute shaders. Voxels without a material only have their color.
+fn shade_hit(hit: &RayHit, direction: Vector3<f32>, materials: &MaterialTable) -> Vector4<f32> {
+    let Some(material) = hit.voxel.material else {
+        return hit.voxel.averaged_color;
+    };
+    let material = materials.get(material);
+    let light_direction = Vector3::new(0.4, 1.0, 0.3).normalize();
+
+    let shininess = 256.0 + (2.0 - 256.0) * material.roughness;
+    // The same as reflect() in GLSL
+    let reflected = -light_direction - 2.0 * hit.normal.dot(&-light_direction) * hit.normal;
+    let highlight = (1.0 - material.roughness) * reflected.dot(&-direction).max(0.0).powf(shininess);
+    let highlight_color = Vector3::repeat(1.0).lerp(&material.base_color, material.metalness);
+    // Metals show most of their color in the highlight, so the rest of the surface is darker
+    let surface_color = material.base_color * (1.0 + (0.5 - 1.0) * material.metalness);
+
+    let color = surface_color + highlight_color * highlight + material.base_color * material.emission;
+    Vector4::new(color.x, color.y, color.z, material.opacity)
+}
+
+impl Chunk {
+    /// The color of the pixel at (`x`, `y`) in an image with the size `width` times `height`, like the compute shaders would draw it.
+    pub fn trace_pixel(&self, camera: &Camera, x: u32, y: u32, width: u32, height: u32) -> Vector4<f32> {
+        self.trace(camera, x, y, width, height).0
+    }
+
+    fn trace(&self, camera: &Camera, x: u32, y: u32, width: u32, height: u32) -> (Vector4<f32>, Option<RayHit<'_>>) {
+        let direction = camera.ray_direction(x, y, width, height);
+        let hit = self.raycast(camera.position(), direction, f32::MAX);
+        (hit.map_or(camera.clear_color, |hit| shade_hit(&hit, direction, &self.materials)), hit)
+    }
+
+    /// Renders the chunk on the CPU, as a reference for what the compute shaders should draw. Every voxel is drawn at the chunk's `depth`,
+    /// without a level of detail. The rows of the image are handed to the thread pool.
+    pub fn render(&self, thread_pool: ThreadPool, camera: &Camera, width: u32, height: u32) -> RgbaImage {
+        self.render_with_g_buffer(thread_pool, camera, width, height).0
+    }
+
+    /// Renders the chunk on the CPU like `render`, together with the G-buffer of what the rays hit.
+    pub fn render_with_g_buffer(&self, thread_pool: ThreadPool, camera: &Camera, width: u32, height: u32) -> (RgbaImage, GBuffer) {
+        // Every row is rendered to its own 1 pixel high G-buffer
+        let rows: Vec<(Vec<u8>, GBuffer)> = thread_pool.scope(|scope| {
+            let handles: Vec<_> = (0..height).map(|y| scope.spawn(move || {
+                let (mut pixels, mut g_buffer) = (Vec::with_capacity((width * 4) as usize), GBuffer::new(width, 1));
+                for x in 0..width {
+                    let (color, hit) = self.trace(camera, x, y, width, height);
+                    pixels.extend(pack_color(color).to_le_bytes());
+                    if let Some(hit) = hit {
+                        g_buffer.depth[x as usize] = hit.t;
+                        g_buffer.normals[x as usize] = hit.normal;
+                        g_buffer.nodes[x as usize] = hit.index;
+                    }
+                }
+                (pixels, g_buffer)
+            })).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        let mut image = RgbaImage { width, height, pixels: Vec::new() };
+        let mut g_buffer = GBuffer { width, height, depth: Vec::new(), normals: Vec::new(), nodes: Vec::new() };
+        for (pixels, row) in rows {
+            image.pixels.extend(pixels);
+            g_buffer.depth.extend(row.depth);
+            g_buffer.normals.extend(row.normals);
+            g_buffer.nodes.extend(row.nodes);
+        }
+        (image, g_buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector2;
+
+    use crate::voxel::material::Material;
+    use super::*;
+
+    #[test]
+    fn single_voxel() {
+        let thread_pool = ThreadPool::new(Some(2));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 4);
+        let cell = chunk.cell_size();
+        // Fully rough, so there is no highlight, and the color is the base color times 0.75 for the metalness plus 0.5 for the emission
+        let material = chunk.add_material(Material { base_color: Vector3::new(0.4, 0.2, 0.1), roughness: 1.0, metalness: 0.5, emission: 0.5, opacity: 1.0 });
+        let range = Vector3::repeat(Vector2::new(8 * cell, 9 * cell));
+        chunk.fill_material(thread_pool.clone(), range, material);
+
+        // Looking down -z at the middle of the voxel, from 3 cells in front of it
+        let center = Vector3::repeat(8.5 * cell as f32);
+        let camera = Camera {
+            field_of_view: 60,
+            aspect_ratio: 1.0,
+            camera_to_world: Matrix4::new_translation(&(center + Vector3::new(0.0, 0.0, 3.5 * cell as f32))),
+            clear_color: Vector4::new(0.0, 0.0, 1.0, 1.0),
+        };
+        let (image, g_buffer) = chunk.render_with_g_buffer(thread_pool, &camera, 9, 9);
+
+        let middle = 4 * 9 + 4;
+        assert_eq!(image.pixel(4, 4), [128, 64, 32, 255]);
+        assert_eq!(g_buffer.depth[middle], 3.0 * cell as f32);
+        assert_eq!(g_buffer.normals[middle], Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(chunk.voxels().get(g_buffer.nodes[middle]).material, Some(material));
+
+        assert_eq!(image.pixel(0, 0), [0, 0, 255, 255]);
+        assert_eq!(g_buffer.depth[0], f32::INFINITY);
+        assert_eq!(g_buffer.normals[0], Vector3::zeros());
+        assert_eq!(g_buffer.nodes[0], u32::MAX);
+    }
+}
@@ -0,0 +1,321 @@
+use std::{fmt, io::{self, Read, Write}};
+
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use super::{material::{Material, MaterialIndex, MaterialTable, MAX_MATERIALS}, pool::{VoxelIndex, VoxelPool}, Chunk, Voxel, CHUNKPOWER, START_VOXEL};
+
+/// The first bytes of every chunk file.
+pub const CHUNK_FILE_MAGIC: [u8; 4] = *b"AWVC";
+/// Increase this when the layout changes, older versions should then still be readable or be rejected explicitly.
+pub const CHUNK_FILE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum ChunkFileError {
+    Io(io::Error),
+    /// The data does not start with `CHUNK_FILE_MAGIC`, so it is not a chunk file.
+    InvalidMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    /// The chunk depth is higher than `CHUNKPOWER`*2.
+    InvalidDepth(u32),
+    /// A voxel at the chunk depth claims to have children.
+    InvalidTree { depth: u32 },
+    /// A voxel refers to a material that is not in the material table.
+    InvalidMaterial(MaterialIndex),
+    /// The material table holds more materials than a `MaterialTable` can.
+    TooManyMaterials(u32),
+}
+
+impl fmt::Display for ChunkFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChunkFileError::Io(e) => write!(f, "failed to read the chunk: {}", e),
+            ChunkFileError::InvalidMagic(magic) => write!(f, "not a chunk file, it starts with {:?}", magic),
+            ChunkFileError::UnsupportedVersion(version) => write!(f, "unsupported chunk file version {}, only version {} is supported", version, CHUNK_FILE_VERSION),
+            ChunkFileError::InvalidDepth(depth) => write!(f, "the chunk depth {} is higher than the supported {}", depth, CHUNKPOWER*2),
+            ChunkFileError::InvalidTree { depth } => write!(f, "a voxel at depth {} has children below the chunk depth", depth),
+            ChunkFileError::InvalidMaterial(material) => write!(f, "a voxel has the material {}, which is not in the material table", material),
+            ChunkFileError::TooManyMaterials(count) => write!(f, "the material table holds {} materials, more than the supported {}", count, MAX_MATERIALS),
+        }
+    }
+}
+
+impl std::error::Error for ChunkFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChunkFileError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ChunkFileError {
+    fn from(e: io::Error) -> Self {
+        ChunkFileError::Io(e)
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0_u8; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    Ok(f32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_color(reader: &mut impl Read) -> io::Result<Vector4<f32>> {
+    Ok(Vector4::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?, read_f32(reader)?))
+}
+
+/// Every material is its base color, roughness, metalness, emission and opacity as f32s.
+fn write_materials(materials: &MaterialTable, writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(&(materials.len() as u32).to_le_bytes())?;
+    for material in materials.iter() {
+        for value in [material.base_color.x, material.base_color.y, material.base_color.z, material.roughness, material.metalness, material.emission, material.opacity] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_material(reader: &mut impl Read, materials: &MaterialTable) -> Result<MaterialIndex, ChunkFileError> {
+    let material = u16::from_le_bytes(read_array(reader)?);
+    if material as usize >= materials.len() {
+        return Err(ChunkFileError::InvalidMaterial(material));
+    }
+    Ok(material)
+}
+
+fn read_materials(reader: &mut impl Read) -> Result<MaterialTable, ChunkFileError> {
+    let count = u32::from_le_bytes(read_array(reader)?);
+    if count as usize > MAX_MATERIALS {
+        return Err(ChunkFileError::TooManyMaterials(count));
+    }
+    let mut materials = MaterialTable::new();
+    for _ in 0..count {
+        let base_color = Vector3::new(read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+        let (roughness, metalness, emission, opacity) = (read_f32(reader)?, read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+        // The voxels refer to the materials by where they are in the file, so equal materials are kept apart
+        materials.push(Material { base_color, roughness, metalness, emission, opacity });
+    }
+    Ok(materials)
+}
+
+impl VoxelPool {
+    /// Writes the voxels in pre-order. Every voxel is a byte with a bit set for every child it has. Voxels with children are followed
+    /// by their averaged color, and solid voxels by their material index as a u16. Every voxel without children is solid except for the
+    /// start voxel of an empty chunk, so the start voxel has a byte that is 1 if it is solid before its material.
+    /// Positions and ranges are not stored since they follow from where the voxel is in the tree.
+    fn traverse_and_write(&self, index: VoxelIndex, current_depth: u32, writer: &mut impl Write) -> io::Result<()> {
+        let voxel = self.get(index);
+        let mut mask = 0_u8;
+        for (i, child) in voxel.children.iter().enumerate() {
+            if child.is_some() {
+                mask |= 1 << i;
+            }
+        }
+        writer.write_all(&[mask])?;
+        if mask != 0 {
+            for value in voxel.averaged_color.iter() {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        } else if current_depth > 0 || voxel.material.is_some() {
+            if current_depth == 0 {
+                writer.write_all(&[1])?;
+            }
+            let material = voxel.material.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "a voxel below the start voxel has neither children nor a material"))?;
+            writer.write_all(&material.to_le_bytes())?;
+        } else {
+            writer.write_all(&[0])?;
+        }
+        for child in voxel.children.iter().flatten() {
+            self.traverse_and_write(*child, current_depth + 1, writer)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the voxel at `index` and adds the children it has. The position and range of the voxel have to be set already.
+    /// The averaged colors are read as they were saved, so none of the voxels are dirty.
+    fn traverse_and_read(&mut self, reader: &mut impl Read, materials: &MaterialTable, index: VoxelIndex, depth: u32, current_depth: u32) -> Result<(), ChunkFileError> {
+        let [mask] = read_array(reader)?;
+        if mask != 0 && current_depth == depth {
+            return Err(ChunkFileError::InvalidTree { depth: current_depth });
+        }
+
+        let voxel = self.get_mut(index);
+        if mask != 0 {
+            voxel.averaged_color = read_color(reader)?;
+        } else if current_depth > 0 || read_array::<1>(reader)? != [0] {
+            voxel.material = Some(read_material(reader, materials)?);
+        }
+        let (pos, size) = (voxel.pos, voxel.range/2.0);
+        for i in 0..8 {
+            if mask & (1 << i) == 0 {
+                continue;
+            }
+            let (x, y, z) = ((i % 2) as f32, (i / 4) as f32, ((i / 2) % 2) as f32);
+            let child_pos = Vector3::new(pos.x + x * size, pos.y + y * size, pos.z + z * size);
+            let child = self.insert(Voxel { dirty: false, ..Voxel::new(child_pos, size) });
+            self.get_mut(index).children[i] = Some(child);
+            self.traverse_and_read(reader, materials, child, depth, current_depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Chunk {
+    /// Writes the chunk in the binary chunk format. The writer is not buffered here, so wrap it in a `BufWriter` when writing to a file.
+    ///
+    /// The format is `CHUNK_FILE_MAGIC`, the version as a u16, the position as two i128s and the depth as a u32,
+    /// followed by the number of materials as a u32, the materials and the voxels.
+    /// All numbers are little endian.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&CHUNK_FILE_MAGIC)?;
+        writer.write_all(&CHUNK_FILE_VERSION.to_le_bytes())?;
+        writer.write_all(&self.position.x.to_le_bytes())?;
+        writer.write_all(&self.position.y.to_le_bytes())?;
+        writer.write_all(&self.depth.to_le_bytes())?;
+        write_materials(&self.materials, &mut writer)?;
+        self.voxels.traverse_and_write(START_VOXEL, 0, &mut writer)
+    }
+
+    /// Reads a chunk written by `Chunk::write_to`. Data that is not a valid chunk file gives an error instead of a panic.
+    pub fn read_from(mut reader: impl Read) -> Result<Chunk, ChunkFileError> {
+        let magic: [u8; 4] = read_array(&mut reader)?;
+        if magic != CHUNK_FILE_MAGIC {
+            return Err(ChunkFileError::InvalidMagic(magic));
+        }
+        let version = u16::from_le_bytes(read_array(&mut reader)?);
+        if version != CHUNK_FILE_VERSION {
+            return Err(ChunkFileError::UnsupportedVersion(version));
+        }
+        let position = Vector2::new(i128::from_le_bytes(read_array(&mut reader)?), i128::from_le_bytes(read_array(&mut reader)?));
+        let depth = u32::from_le_bytes(read_array(&mut reader)?);
+        if depth > CHUNKPOWER*2 {
+            return Err(ChunkFileError::InvalidDepth(depth));
+        }
+
+        let mut chunk = Chunk::new(position, depth);
+        chunk.materials = read_materials(&mut reader)?;
+        chunk.voxels.traverse_and_read(&mut reader, &chunk.materials, START_VOXEL, depth, 0)?;
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{Vector2, Vector3, Vector4};
+
+    use crate::{threadpool::ThreadPool, voxel::CHUNKSIZE};
+    use super::*;
+
+    fn range(x: (u32, u32), y: (u32, u32), z: (u32, u32)) -> Vector3<Vector2<u32>> {
+        Vector3::new(Vector2::new(x.0, x.1), Vector2::new(y.0, y.1), Vector2::new(z.0, z.1))
+    }
+
+    fn save(chunk: &Chunk) -> Vec<u8> {
+        let mut data = vec![];
+        chunk.write_to(&mut data).unwrap();
+        data
+    }
+
+    fn test_chunk() -> Chunk {
+        let thread_pool = ThreadPool::new(Some(2));
+        let mut chunk = Chunk::new(Vector2::new(-3, 7), 5);
+        let cell = chunk.cell_size();
+        chunk.fill_voxels(thread_pool.clone(), range((0, 20 * cell), (0, 4 * cell), (3 * cell, 30 * cell)), Vector4::new(0.2, 0.6, 0.1, 1.0));
+        chunk.fill_voxels(thread_pool.clone(), range((5 * cell, 9 * cell), (2 * cell, 12 * cell), (0, 32 * cell)), Vector4::new(0.9, 0.1, 0.1, 0.5));
+        chunk.clear_voxels(thread_pool.clone(), range((6 * cell, 7 * cell), (0, 32 * cell), (10 * cell, 20 * cell)));
+        let lava = chunk.add_material(Material { base_color: Vector3::new(1.0, 0.3, 0.0), roughness: 0.4, metalness: 0.0, emission: 4.0, opacity: 1.0 });
+        chunk.fill_material(thread_pool, range((16 * cell, 32 * cell), (16 * cell, 32 * cell), (16 * cell, 32 * cell)), lava);
+        chunk
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = test_chunk();
+        assert_eq!(Chunk::read_from(&save(&chunk)[..]).unwrap(), chunk);
+    }
+
+    #[test]
+    fn round_trip_empty_and_depth_0() {
+        let empty = Chunk::new(Vector2::new(1, 2), 8);
+        assert_eq!(Chunk::read_from(&save(&empty)[..]).unwrap(), empty);
+
+        let mut solid = Chunk::new(Vector2::new(0, 0), 0);
+        solid.fill_voxels(ThreadPool::new(Some(0)), range((0, CHUNKSIZE), (0, CHUNKSIZE), (0, CHUNKSIZE)), Vector4::new(1.0, 1.0, 0.0, 1.0));
+        assert!(solid.start_voxel().material.is_some());
+        assert_eq!(Chunk::read_from(&save(&solid)[..]).unwrap(), solid);
+    }
+
+    #[test]
+    fn edits_after_reading_only_touch_their_subtree() {
+        let mut chunk = Chunk::read_from(&save(&test_chunk())[..]).unwrap();
+        let cell = chunk.cell_size();
+        let edit = range((cell, 2 * cell), (cell, 2 * cell), (4 * cell, 5 * cell));
+        chunk.set_voxels_deferred(ThreadPool::new(Some(0)), edit, Some(Vector4::new(0.0, 0.0, 1.0, 1.0)));
+
+        // Only the voxels on the way down to the edit need new averaged colors, solid voxels have none
+        let overlaps = |voxel: &Voxel| (0..3).all(|axis| voxel.pos[axis] < edit[axis].y as f32 && voxel.pos[axis] + voxel.range > edit[axis].x as f32);
+        let mut stack = vec![chunk.start_voxel()];
+        let mut dirty = 0;
+        while let Some(voxel) = stack.pop() {
+            if voxel.dirty && !voxel.is_leaf() {
+                assert!(overlaps(voxel), "the voxel at {:?} is dirty, but it is not edited", voxel.pos);
+                dirty += 1;
+            }
+            stack.extend(chunk.voxels().children(voxel));
+        }
+        assert!(dirty > 0);
+    }
+
+    #[test]
+    fn equal_materials_keep_their_indices() {
+        let mut data = save(&test_chunk());
+        // Material 1 becomes a copy of material 0, which `MaterialTable::add` would have merged
+        let materials = 4 + 2 + 32 + 4 + 4;
+        let material_size = 7 * 4;
+        data.copy_within(materials..materials + material_size, materials + material_size);
+        let chunk = Chunk::read_from(&data[..]).unwrap();
+        assert_eq!(chunk.materials().len(), 3);
+        assert_eq!(chunk.materials().get(0), chunk.materials().get(1));
+        assert!(chunk.get_leaves().iter().any(|leaf| leaf.material == Some(1)));
+    }
+
+    #[test]
+    fn invalid_magic() {
+        let mut data = save(&test_chunk());
+        data[..4].copy_from_slice(b"RIFF");
+        assert!(matches!(Chunk::read_from(&data[..]), Err(ChunkFileError::InvalidMagic(magic)) if &magic == b"RIFF"));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = save(&test_chunk());
+        for version in [0, CHUNK_FILE_VERSION + 1, u16::MAX] {
+            data[4..6].copy_from_slice(&version.to_le_bytes());
+            assert!(matches!(Chunk::read_from(&data[..]), Err(ChunkFileError::UnsupportedVersion(v)) if v == version));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = save(&test_chunk());
+        for len in 0..data.len() {
+            assert!(matches!(Chunk::read_from(&data[..len]), Err(ChunkFileError::Io(_))), "a file cut at {} of {} bytes was read", len, data.len());
+        }
+    }
+
+    #[test]
+    fn invalid_depth_and_tree() {
+        let mut data = save(&test_chunk());
+        let depth = 4 + 2 + 32;
+        data[depth..depth + 4].copy_from_slice(&(CHUNKPOWER*2 + 1).to_le_bytes());
+        assert!(matches!(Chunk::read_from(&data[..]), Err(ChunkFileError::InvalidDepth(d)) if d == CHUNKPOWER*2 + 1));
+
+        // The voxels at depth 2 of the chunk have children, which a chunk with a depth of 2 can not have
+        data[depth..depth + 4].copy_from_slice(&2_u32.to_le_bytes());
+        assert!(matches!(Chunk::read_from(&data[..]), Err(ChunkFileError::InvalidTree { depth: 2 })));
+    }
+}
@@ -0,0 +1,627 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, io::{self, Read, Write}};
+
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use crate::threadpool::ThreadPool;
+
+use super::{Chunk, CHUNKSIZE};
+
+// MagicaVoxel's format is described here: https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt
+
+pub const VOX_MAGIC: [u8; 4] = *b"VOX ";
+
+#[derive(Debug)]
+pub enum VoxError {
+    Io(io::Error),
+    /// The data does not start with `VOX_MAGIC`, so it is not a .vox file.
+    InvalidMagic([u8; 4]),
+    /// The file is cut off or a chunk in it does not match its own size.
+    Corrupt(&'static str),
+    /// The scene is taller than `CHUNKSIZE`, chunks can only be placed next to each other and not on top of each other.
+    TooTall(u32),
+    /// A solid voxel of the chunk covers more cells along each axis than the 256 a model can hold, so it is not expanded into cells.
+    TooBig(u64),
+}
+
+impl fmt::Display for VoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoxError::Io(e) => write!(f, "failed to read the .vox file: {}", e),
+            VoxError::InvalidMagic(magic) => write!(f, "not a .vox file, it starts with {:?}", magic),
+            VoxError::Corrupt(reason) => write!(f, "corrupt .vox file: {}", reason),
+            VoxError::TooTall(height) => write!(f, "the scene is {} voxels tall, but a chunk is only {} tall", height, CHUNKSIZE),
+            VoxError::TooBig(cells) => write!(f, "a voxel covers {} cells, but a model can only hold {}", cells, 256_u64.pow(3)),
+        }
+    }
+}
+
+impl std::error::Error for VoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VoxError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VoxError {
+    fn from(e: io::Error) -> Self {
+        VoxError::Io(e)
+    }
+}
+
+/// A single model, in MagicaVoxel's axes where z is up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxModel {
+    pub size: Vector3<u32>,
+    /// The position and palette index of every voxel in the model.
+    pub voxels: Vec<(Vector3<u8>, u8)>,
+}
+
+/// A placement of a model in the scene, in MagicaVoxel's axes where z is up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VoxInstance {
+    pub model: usize,
+    /// Where the center of the model is placed. Like MagicaVoxel, the center is `size/2` rounded down.
+    pub translation: Vector3<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoxScene {
+    pub models: Vec<VoxModel>,
+    /// Files without a scene graph get one instance per model, all placed at the origin.
+    pub instances: Vec<VoxInstance>,
+    /// The colors of the palette indices. Index 0 means empty and is never used by voxels.
+    pub palette: [Vector4<f32>; 256],
+}
+
+/// MagicaVoxel's default palette, used when a file has no RGBA chunk.
+/// It is a 6x6x6 color cube without black, followed by red, green, blue and gray ramps.
+pub fn default_palette() -> [Vector4<f32>; 256] {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut colors: Vec<[u8; 3]> = vec![];
+    for r in CUBE {
+        for g in CUBE {
+            for b in CUBE {
+                if r != 0 || g != 0 || b != 0 {
+                    colors.push([r, g, b]);
+                }
+            }
+        }
+    }
+    colors.extend(RAMP.iter().map(|&v| [v, 0, 0]));
+    colors.extend(RAMP.iter().map(|&v| [0, v, 0]));
+    colors.extend(RAMP.iter().map(|&v| [0, 0, v]));
+    colors.extend(RAMP.iter().map(|&v| [v, v, v]));
+
+    let mut palette = [Vector4::new(0.0, 0.0, 0.0, 0.0); 256];
+    for (i, [r, g, b]) in colors.into_iter().enumerate() {
+        palette[i + 1] = color_from_rgba([r, g, b, 0xff]);
+    }
+    palette
+}
+
+fn color_from_rgba(rgba: [u8; 4]) -> Vector4<f32> {
+    Vector4::new(rgba[0] as f32/255.0, rgba[1] as f32/255.0, rgba[2] as f32/255.0, rgba[3] as f32/255.0)
+}
+
+/// Converts from MagicaVoxel's axes, where z is up, to the engine's axes, where y is up.
+/// This rotates the axes instead of swapping y and z, so that models are not mirrored.
+pub fn vox_to_engine_axes<T: Copy>(v: Vector3<T>) -> Vector3<T> {
+    Vector3::new(v[1], v[2], v[0])
+}
+
+/// The inverse of `vox_to_engine_axes`.
+pub fn engine_to_vox_axes<T: Copy>(v: Vector3<T>) -> Vector3<T> {
+    Vector3::new(v[2], v[0], v[1])
+}
+
+/// Quantizes a color to 8 bits per channel, the way MagicaVoxel stores it.
+fn color_to_rgba(color: Vector4<f32>) -> [u8; 4] {
+    let channel = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    [channel(color.x), channel(color.y), channel(color.z), channel(color.w)]
+}
+
+/// Reduces the colors to at most 255 using median cut, weighted by how many voxels use each color.
+/// Returns the palette (without the empty index 0) and the palette index of every color.
+fn quantize_colors(counts: &HashMap<[u8; 4], usize>) -> (Vec<[u8; 4]>, HashMap<[u8; 4], u8>) {
+    let mut colors: Vec<([u8; 4], usize)> = counts.iter().map(|(color, count)| (*color, *count)).collect();
+    // Sorted so that the palette does not depend on the order of the hash map
+    colors.sort();
+
+    if colors.len() <= 255 {
+        let lookup = colors.iter().enumerate().map(|(i, (color, _))| (*color, i as u8 + 1)).collect();
+        return (colors.into_iter().map(|(color, _)| color).collect(), lookup);
+    }
+
+    let spread = |colors: &[([u8; 4], usize)], channel: usize| {
+        let min = colors.iter().map(|(color, _)| color[channel]).min().unwrap();
+        let max = colors.iter().map(|(color, _)| color[channel]).max().unwrap();
+        max - min
+    };
+
+    let mut boxes: Vec<Vec<([u8; 4], usize)>> = vec![colors];
+    while boxes.len() < 255 {
+        // Split the box that is widest along any channel
+        let Some((index, channel, _)) = boxes.iter().enumerate()
+            .filter(|(_, colors)| colors.len() > 1)
+            .flat_map(|(i, colors)| (0..4).map(move |channel| (i, channel, spread(colors, channel))))
+            .max_by_key(|(_, _, spread)| *spread) else {
+            break;
+        };
+
+        let mut colors = boxes.swap_remove(index);
+        colors.sort_by_key(|(color, _)| color[channel]);
+        let total: usize = colors.iter().map(|(_, count)| count).sum();
+        let mut seen = 0;
+        let mut split = colors.len() - 1;
+        for (i, (_, count)) in colors.iter().enumerate() {
+            seen += count;
+            if seen * 2 >= total {
+                split = i + 1;
+                break;
+            }
+        }
+        let upper = colors.split_off(split.clamp(1, colors.len() - 1));
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    let mut palette = vec![];
+    let mut lookup = HashMap::new();
+    for (i, colors) in boxes.iter().enumerate() {
+        let total: usize = colors.iter().map(|(_, count)| count).sum();
+        let mut average = [0_usize; 4];
+        for (color, count) in colors {
+            for channel in 0..4 {
+                average[channel] += color[channel] as usize * count;
+            }
+        }
+        palette.push(average.map(|sum| ((sum + total/2) / total) as u8));
+        for (color, _) in colors {
+            lookup.insert(*color, i as u8 + 1);
+        }
+    }
+    (palette, lookup)
+}
+
+fn push_i32(out: &mut Vec<u8>, values: &[i32]) {
+    for value in values {
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn push_vox_chunk(out: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    push_i32(out, &[content.len() as i32, 0]);
+    out.extend_from_slice(content);
+}
+
+fn push_vox_dict(out: &mut Vec<u8>, dict: &[(&str, String)]) {
+    push_i32(out, &[dict.len() as i32]);
+    for (key, value) in dict {
+        for string in [key.as_bytes(), value.as_bytes()] {
+            push_i32(out, &[string.len() as i32]);
+            out.extend_from_slice(string);
+        }
+    }
+}
+
+/// Reads little endian values out of the content of a .vox chunk, failing instead of reading past the end.
+struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], VoxError> {
+        if len > self.data.len() {
+            return Err(VoxError::Corrupt("a chunk ends before its content does"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, VoxError> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize, VoxError> {
+        usize::try_from(self.i32()?).map_err(|_| VoxError::Corrupt("negative length"))
+    }
+
+    fn string(&mut self) -> Result<String, VoxError> {
+        let len = self.len()?;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+
+    fn dict(&mut self) -> Result<HashMap<String, String>, VoxError> {
+        let mut dict = HashMap::new();
+        for _ in 0..self.len()? {
+            let key = self.string()?;
+            dict.insert(key, self.string()?);
+        }
+        Ok(dict)
+    }
+}
+
+#[derive(Debug)]
+enum SceneNode {
+    Transform { child: i32, translation: Vector3<i32> },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<usize> },
+}
+
+fn parse_translation(frame: &HashMap<String, String>) -> Result<Vector3<i32>, VoxError> {
+    let Some(value) = frame.get("_t") else {
+        return Ok(Vector3::zeros());
+    };
+    let parts: Vec<i32> = value.split_whitespace().map(|part| part.parse()).collect::<Result<_, _>>().map_err(|_| VoxError::Corrupt("invalid translation"))?;
+    match parts[..] {
+        [x, y, z] => Ok(Vector3::new(x, y, z)),
+        _ => Err(VoxError::Corrupt("invalid translation")),
+    }
+}
+
+fn collect_instances(nodes: &HashMap<i32, SceneNode>, id: i32, translation: Vector3<i32>, remaining_depth: usize, instances: &mut Vec<VoxInstance>) -> Result<(), VoxError> {
+    // The graph is supposed to be a tree, this stops files with cycles in it
+    if remaining_depth == 0 {
+        return Err(VoxError::Corrupt("the scene graph has a cycle"));
+    }
+    match nodes.get(&id) {
+        Some(SceneNode::Transform { child, translation: offset }) => {
+            let translation = Vector3::new(translation.x.checked_add(offset.x), translation.y.checked_add(offset.y), translation.z.checked_add(offset.z));
+            let (Some(x), Some(y), Some(z)) = (translation.x, translation.y, translation.z) else {
+                return Err(VoxError::Corrupt("a translation is out of range"));
+            };
+            collect_instances(nodes, *child, Vector3::new(x, y, z), remaining_depth - 1, instances)?
+        },
+        Some(SceneNode::Group { children }) => {
+            for child in children {
+                collect_instances(nodes, *child, translation, remaining_depth - 1, instances)?;
+            }
+        },
+        Some(SceneNode::Shape { models }) => instances.extend(models.iter().map(|&model| VoxInstance { model, translation })),
+        None => return Err(VoxError::Corrupt("the scene graph refers to a node that does not exist")),
+    }
+    Ok(())
+}
+
+impl VoxScene {
+    /// Reads a MagicaVoxel .vox file. The SIZE, XYZI and RGBA chunks are read, together with the translations in the scene graph.
+    /// Rotations, materials and layers are ignored.
+    pub fn read_from(mut reader: impl Read) -> Result<VoxScene, VoxError> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        let mut file = ByteReader { data: &data };
+
+        let magic: [u8; 4] = file.bytes(4)?.try_into().unwrap();
+        if magic != VOX_MAGIC {
+            return Err(VoxError::InvalidMagic(magic));
+        }
+        let _version = file.i32()?;
+        if file.bytes(4)? != b"MAIN" {
+            return Err(VoxError::Corrupt("the MAIN chunk is missing"));
+        }
+        let main_content_len = file.len()?;
+        let main_children_len = file.len()?;
+        file.bytes(main_content_len)?;
+        let mut chunks = ByteReader { data: file.bytes(main_children_len)? };
+
+        let mut sizes: Vec<Vector3<u32>> = vec![];
+        let mut models: Vec<VoxModel> = vec![];
+        let mut nodes: HashMap<i32, SceneNode> = HashMap::new();
+        let mut palette = default_palette();
+
+        while !chunks.data.is_empty() {
+            let id: [u8; 4] = chunks.bytes(4)?.try_into().unwrap();
+            let content_len = chunks.len()?;
+            let children_len = chunks.len()?;
+            let mut content = ByteReader { data: chunks.bytes(content_len)? };
+            chunks.bytes(children_len)?;
+
+            match &id {
+                b"SIZE" => {
+                    let (x, y, z) = (content.len()?, content.len()?, content.len()?);
+                    if x > 256 || y > 256 || z > 256 {
+                        return Err(VoxError::Corrupt("a model is bigger than 256 voxels"));
+                    }
+                    sizes.push(Vector3::new(x as u32, y as u32, z as u32));
+                },
+                b"XYZI" => {
+                    let Some(&size) = sizes.get(models.len()) else {
+                        return Err(VoxError::Corrupt("an XYZI chunk has no SIZE chunk before it"));
+                    };
+                    let num_voxels = content.len()?;
+                    let bytes = content.bytes(num_voxels.checked_mul(4).ok_or(VoxError::Corrupt("too many voxels"))?)?;
+                    let mut voxels = Vec::with_capacity(num_voxels);
+                    for voxel in bytes.chunks_exact(4) {
+                        if voxel[0] as u32 >= size.x || voxel[1] as u32 >= size.y || voxel[2] as u32 >= size.z {
+                            return Err(VoxError::Corrupt("a voxel is outside of its model"));
+                        }
+                        voxels.push((Vector3::new(voxel[0], voxel[1], voxel[2]), voxel[3]));
+                    }
+                    models.push(VoxModel { size, voxels });
+                },
+                b"RGBA" => {
+                    // Palette index i is stored at i-1, the last color in the chunk is unused
+                    let colors = content.bytes(256*4)?;
+                    for (i, rgba) in colors.chunks_exact(4).take(255).enumerate() {
+                        palette[i + 1] = color_from_rgba(rgba.try_into().unwrap());
+                    }
+                },
+                b"nTRN" => {
+                    let node_id = content.i32()?;
+                    content.dict()?;
+                    let child = content.i32()?;
+                    let _reserved = content.i32()?;
+                    let _layer = content.i32()?;
+                    let num_frames = content.len()?;
+                    let translation = if num_frames > 0 { parse_translation(&content.dict()?)? } else { Vector3::zeros() };
+                    nodes.insert(node_id, SceneNode::Transform { child, translation });
+                },
+                b"nGRP" => {
+                    let node_id = content.i32()?;
+                    content.dict()?;
+                    let children = (0..content.len()?).map(|_| content.i32()).collect::<Result<_, _>>()?;
+                    nodes.insert(node_id, SceneNode::Group { children });
+                },
+                b"nSHP" => {
+                    let node_id = content.i32()?;
+                    content.dict()?;
+                    let mut shape_models = vec![];
+                    for _ in 0..content.len()? {
+                        shape_models.push(content.len()?);
+                        content.dict()?;
+                    }
+                    nodes.insert(node_id, SceneNode::Shape { models: shape_models });
+                },
+                _ => (),
+            }
+        }
+
+        let instances = if nodes.is_empty() {
+            (0..models.len()).map(|model| VoxInstance { model, translation: Vector3::zeros() }).collect()
+        } else {
+            let mut instances = vec![];
+            collect_instances(&nodes, 0, Vector3::zeros(), nodes.len(), &mut instances)?;
+            instances
+        };
+        if instances.iter().any(|instance| instance.model >= models.len()) {
+            return Err(VoxError::Corrupt("a shape refers to a model that does not exist"));
+        }
+
+        Ok(VoxScene { models, instances, palette })
+    }
+
+    /// Converts the cells at the chunk's `depth` into a scene, where one cell becomes one voxel.
+    /// The colors are reduced to MagicaVoxel's 255 palette colors, and the cells are split into models of at most 256 voxels along each axis.
+    /// The positions are relative to the chunk, so the chunk's `position` is not part of the scene.
+    /// Solid voxels that cover more than 256^3 cells give an error instead of being expanded, since a chunk can have voxels far too big for that.
+    pub fn from_chunk(chunk: &Chunk) -> Result<VoxScene, VoxError> {
+        let cell_size = chunk.cell_size();
+
+        let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+        let mut cells: Vec<(Vector3<u32>, [u8; 4])> = vec![];
+        for leaf in chunk.get_leaves() {
+            let rgba = color_to_rgba(leaf.color(chunk.materials()));
+            let start = leaf.pos.map(|v| v as u32 / cell_size);
+            let cells_per_axis = (leaf.range as u32 / cell_size).max(1);
+            let cell_count = (cells_per_axis as u64).pow(3);
+            if cells_per_axis > 256 {
+                return Err(VoxError::TooBig(cell_count));
+            }
+            *counts.entry(rgba).or_insert(0) += cell_count as usize;
+            for x in 0..cells_per_axis {
+                for y in 0..cells_per_axis {
+                    for z in 0..cells_per_axis {
+                        cells.push((engine_to_vox_axes(start + Vector3::new(x, y, z)), rgba));
+                    }
+                }
+            }
+        }
+        let (colors, lookup) = quantize_colors(&counts);
+
+        // The voxels with their palette index, grouped by which 256^3 block they are in
+        type Block = Vec<(Vector3<u32>, u8)>;
+        let mut blocks: BTreeMap<(u32, u32, u32), Block> = BTreeMap::new();
+        for (cell, rgba) in cells {
+            blocks.entry((cell.x / 256, cell.y / 256, cell.z / 256)).or_default().push((cell, lookup[&rgba]));
+        }
+
+        let mut models = vec![];
+        let mut instances = vec![];
+        for voxels in blocks.into_values() {
+            // The models only cover the voxels that are used in their block
+            let min = voxels.iter().fold(Vector3::repeat(u32::MAX), |min, (cell, _)| min.inf(cell));
+            let max = voxels.iter().fold(Vector3::repeat(0), |max, (cell, _)| max.sup(cell));
+            let size = max - min + Vector3::repeat(1);
+            instances.push(VoxInstance { model: models.len(), translation: (min + size/2).cast::<i32>() });
+            models.push(VoxModel { size, voxels: voxels.into_iter().map(|(cell, index)| ((cell - min).map(|v| v as u8), index)).collect() });
+        }
+
+        let mut palette = [Vector4::new(0.0, 0.0, 0.0, 0.0); 256];
+        for (i, rgba) in colors.into_iter().enumerate() {
+            palette[i + 1] = color_from_rgba(rgba);
+        }
+
+        Ok(VoxScene { models, instances, palette })
+    }
+
+    /// Writes the scene as a MagicaVoxel .vox file, with a scene graph that places every instance.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        let mut children = vec![];
+        if self.models.len() > 1 {
+            push_vox_chunk(&mut children, b"PACK", &(self.models.len() as i32).to_le_bytes());
+        }
+        for model in self.models.iter() {
+            let mut size = vec![];
+            push_i32(&mut size, &[model.size.x as i32, model.size.y as i32, model.size.z as i32]);
+            push_vox_chunk(&mut children, b"SIZE", &size);
+            let mut xyzi = vec![];
+            push_i32(&mut xyzi, &[model.voxels.len() as i32]);
+            for (pos, index) in model.voxels.iter() {
+                xyzi.extend_from_slice(&[pos.x, pos.y, pos.z, *index]);
+            }
+            push_vox_chunk(&mut children, b"XYZI", &xyzi);
+        }
+
+        // The root transform (node 0) points to a group (node 1) with a transform and a shape for every instance
+        let mut root = vec![];
+        push_i32(&mut root, &[0]);
+        push_vox_dict(&mut root, &[]);
+        push_i32(&mut root, &[1, -1, -1, 1]);
+        push_vox_dict(&mut root, &[]);
+        push_vox_chunk(&mut children, b"nTRN", &root);
+
+        let mut group = vec![];
+        push_i32(&mut group, &[1]);
+        push_vox_dict(&mut group, &[]);
+        push_i32(&mut group, &[self.instances.len() as i32]);
+        for i in 0..self.instances.len() as i32 {
+            push_i32(&mut group, &[2 + 2*i]);
+        }
+        push_vox_chunk(&mut children, b"nGRP", &group);
+
+        for (i, instance) in self.instances.iter().enumerate() {
+            let transform_id = 2 + 2*i as i32;
+            let t = instance.translation;
+            let mut transform = vec![];
+            push_i32(&mut transform, &[transform_id]);
+            push_vox_dict(&mut transform, &[]);
+            push_i32(&mut transform, &[transform_id + 1, -1, 0, 1]);
+            push_vox_dict(&mut transform, &[("_t", format!("{} {} {}", t.x, t.y, t.z))]);
+            push_vox_chunk(&mut children, b"nTRN", &transform);
+
+            let mut shape = vec![];
+            push_i32(&mut shape, &[transform_id + 1]);
+            push_vox_dict(&mut shape, &[]);
+            push_i32(&mut shape, &[1, instance.model as i32]);
+            push_vox_dict(&mut shape, &[]);
+            push_vox_chunk(&mut children, b"nSHP", &shape);
+        }
+
+        // Palette index i is stored at i-1
+        let mut rgba: Vec<u8> = self.palette[1..].iter().flat_map(|color| color_to_rgba(*color)).collect();
+        rgba.extend_from_slice(&[0, 0, 0, 0]);
+        push_vox_chunk(&mut children, b"RGBA", &rgba);
+
+        let mut header = VOX_MAGIC.to_vec();
+        push_i32(&mut header, &[150]);
+        header.extend_from_slice(b"MAIN");
+        push_i32(&mut header, &[0, children.len() as i32]);
+        writer.write_all(&header)?;
+        writer.write_all(&children)
+    }
+
+    /// Gets every voxel in the scene with its palette index, in the engine's axes.
+    /// The positions are placed so that the lowest corner of the scene is at (0, 0, 0), wherever the instances are in the file.
+    pub fn engine_voxels(&self) -> Result<Vec<(Vector3<i64>, u8)>, VoxError> {
+        let mut voxels = vec![];
+        for instance in self.instances.iter() {
+            let model = &self.models[instance.model];
+            let corner = instance.translation.cast::<i64>() - (model.size/2).cast::<i64>();
+            for (pos, index) in model.voxels.iter() {
+                voxels.push((vox_to_engine_axes(corner + pos.cast::<i64>()), *index));
+            }
+        }
+
+        if voxels.is_empty() {
+            return Ok(voxels);
+        }
+        let min = voxels.iter().fold(Vector3::repeat(i64::MAX), |min, (pos, _)| min.inf(pos));
+        let max = voxels.iter().fold(Vector3::repeat(i64::MIN), |max, (pos, _)| max.sup(pos));
+        let height = u32::try_from(max.y - min.y + 1).unwrap_or(u32::MAX);
+        if height > CHUNKSIZE {
+            return Err(VoxError::TooTall(height));
+        }
+        for (pos, _) in voxels.iter_mut() {
+            *pos -= min;
+        }
+        Ok(voxels)
+    }
+
+    /// Fills chunks with the voxels of the scene, one voxel in the file becomes one unit in the chunk, placed like `engine_voxels` does.
+    /// Scenes that are wider than `CHUNKSIZE` are split into several chunks, where the `position` of a chunk is its x and z index.
+    pub fn to_chunks(&self, thread_pool: ThreadPool, depth: u32) -> Result<Vec<Chunk>, VoxError> {
+        let mut chunks: BTreeMap<(i128, i128), Chunk> = BTreeMap::new();
+        for (pos, index) in self.engine_voxels()? {
+            let chunk_pos = (pos.x.div_euclid(CHUNKSIZE as i64) as i128, pos.z.div_euclid(CHUNKSIZE as i64) as i128);
+            let local = Vector3::new(pos.x.rem_euclid(CHUNKSIZE as i64) as u32, pos.y as u32, pos.z.rem_euclid(CHUNKSIZE as i64) as u32);
+            let chunk = chunks.entry(chunk_pos).or_insert_with(|| Chunk::new(Vector2::new(chunk_pos.0, chunk_pos.1), depth));
+            // The colors are averaged once per chunk at the end instead of after every voxel
+            let range = Vector3::new(Vector2::new(local.x, local.x + 1), Vector2::new(local.y, local.y + 1), Vector2::new(local.z, local.z + 1));
+            chunk.set_voxels_deferred(thread_pool.clone(), range, Some(self.palette[index as usize]));
+        }
+
+        Ok(chunks.into_values().map(|mut chunk| {
+            chunk.recalculate_colors(thread_pool.clone());
+            chunk
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::voxel::CHUNKPOWER;
+    use super::*;
+
+    fn cube(size: u8) -> VoxModel {
+        let mut voxels = vec![];
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    voxels.push((Vector3::new(x, y, z), 1 + x % 3));
+                }
+            }
+        }
+        VoxModel { size: Vector3::repeat(size as u32), voxels }
+    }
+
+    #[test]
+    fn scene_is_moved_into_one_chunk() {
+        // The instances are on both sides of the origin, and far below it
+        let scene = VoxScene {
+            models: vec![cube(4), cube(6)],
+            instances: vec![
+                VoxInstance { model: 0, translation: Vector3::new(-40, -25, -300) },
+                VoxInstance { model: 1, translation: Vector3::new(10, 30, -290) },
+            ],
+            palette: default_palette(),
+        };
+        let mut data = vec![];
+        scene.write_to(&mut data).unwrap();
+        let scene = VoxScene::read_from(&data[..]).unwrap();
+
+        let voxels = scene.engine_voxels().unwrap();
+        let min = voxels.iter().map(|(pos, _)| *pos).reduce(|a, b| a.inf(&b)).unwrap();
+        assert_eq!(min, Vector3::zeros());
+
+        let chunks = scene.to_chunks(ThreadPool::new(Some(0)), 16).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].position, Vector2::new(0, 0));
+        for (pos, index) in voxels {
+            let voxel = chunks[0].get_voxel(pos.x as u32, pos.y as u32, pos.z as u32).unwrap();
+            assert_eq!(voxel.color(chunks[0].materials()), scene.palette[index as usize]);
+        }
+    }
+
+    #[test]
+    fn big_voxels_are_rejected() {
+        let thread_pool = ThreadPool::new(Some(0));
+        let mut chunk = Chunk::new(Vector2::new(0, 0), CHUNKPOWER*2);
+        let whole = Vector2::new(0, CHUNKSIZE);
+        chunk.fill_voxels(thread_pool.clone(), Vector3::new(whole, whole, whole), Vector4::new(1.0, 0.0, 0.0, 1.0));
+        let half = (CHUNKSIZE/2) as u64;
+        assert!(matches!(VoxScene::from_chunk(&chunk), Err(VoxError::TooBig(cells)) if cells == half.pow(3)));
+
+        // The same fill in a chunk with 16 cells along each axis
+        let mut chunk = Chunk::new(Vector2::new(0, 0), 4);
+        chunk.fill_voxels(thread_pool, Vector3::new(whole, whole, whole), Vector4::new(1.0, 0.0, 0.0, 1.0));
+        let scene = VoxScene::from_chunk(&chunk).unwrap();
+        assert_eq!(scene.models.len(), 1);
+        assert_eq!(scene.models[0].voxels.len(), 16_usize.pow(3));
+    }
+}
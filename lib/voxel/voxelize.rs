@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use crate::threadpool::ThreadPool;
+
+use super::{mesh::Mesh, Chunk, CHUNKSIZE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoxelizeMode {
+    /// Only the cells the triangles pass through are filled.
+    Surface,
+    /// The inside of the mesh is filled as well. The mesh has to be closed for this to work, the inside is found by counting how many
+    /// triangles a line along the y axis crosses.
+    Solid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoxelizeColors {
+    /// Every voxel gets the same color.
+    Uniform(Vector4<f32>),
+    /// Every voxel gets the average of the vertex colors of the triangle it came from.
+    PerTriangle,
+    /// Every voxel gets the vertex colors of the triangle it came from blended at the point on the triangle closest to the voxel.
+    PerVertex,
+}
+
+/// Checks if a triangle overlaps an axis aligned box, using the separating axis test from
+/// Akenine-Möller's "Fast 3D Triangle-Box Overlap Testing".
+pub fn triangle_box_overlap(box_center: Vector3<f32>, box_half_size: f32, triangle: [Vector3<f32>; 3]) -> bool {
+    let v = triangle.map(|v| v - box_center);
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    let mut axes = vec![Vector3::x(), Vector3::y(), Vector3::z(), edges[0].cross(&edges[1])];
+    for edge in edges {
+        for box_axis in [Vector3::x(), Vector3::y(), Vector3::z()] {
+            axes.push(edge.cross(&box_axis));
+        }
+    }
+
+    axes.iter().all(|axis| {
+        // Edges parallel to a box axis give no axis to test
+        if axis.norm_squared() == 0.0 {
+            return true;
+        }
+        let projected = v.map(|v| v.dot(axis));
+        let min = projected.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = projected.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let radius = box_half_size * (axis.x.abs() + axis.y.abs() + axis.z.abs());
+        min <= radius && max >= -radius
+    })
+}
+
+/// The barycentric weights of the point on the triangle closest to `p`, from Ericson's "Real-Time Collision Detection".
+pub fn closest_point_weights(p: Vector3<f32>, triangle: [Vector3<f32>; 3]) -> Vector3<f32> {
+    let [a, b, c] = triangle;
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(&ap), ac.dot(&ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return Vector3::new(1.0, 0.0, 0.0);
+    }
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(&bp), ac.dot(&bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return Vector3::new(0.0, 1.0, 0.0);
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let v = d1 / (d1 - d3);
+        return Vector3::new(1.0 - v, v, 0.0);
+    }
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(&cp), ac.dot(&cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return Vector3::new(0.0, 0.0, 1.0);
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let w = d2 / (d2 - d6);
+        return Vector3::new(1.0 - w, 0.0, w);
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return Vector3::new(0.0, 1.0 - w, w);
+    }
+    let denom = 1.0 / (va + vb + vc);
+    let (v, w) = (vb * denom, vc * denom);
+    if !(v.is_finite() && w.is_finite()) {
+        // Degenerate triangles have no area to blend over
+        return Vector3::new(1.0, 0.0, 0.0);
+    }
+    Vector3::new(1.0 - v - w, v, w)
+}
+
+/// Finds where the line through (`x`, `z`) along the y axis crosses the triangle.
+/// Points on an edge only count for one of the triangles sharing that edge, so that closed meshes are crossed an even number of times.
+fn crossing_height(x: f32, z: f32, triangle: [Vector3<f32>; 3]) -> Option<f32> {
+    let mut t = triangle;
+    let area = |a: Vector3<f32>, b: Vector3<f32>, x: f32, z: f32| (b.x - a.x) * (z - a.z) - (b.z - a.z) * (x - a.x);
+    let total = area(t[0], t[1], t[2].x, t[2].z);
+    if total == 0.0 {
+        return None;
+    }
+    if total < 0.0 {
+        t.swap(1, 2);
+    }
+    let total = total.abs();
+
+    let mut weights = [0.0; 3];
+    for i in 0..3 {
+        let (a, b) = (t[(i + 1) % 3], t[(i + 2) % 3]);
+        let w = area(a, b, x, z);
+        // The top-left rule: points on an edge belong to the triangle if the edge is a top edge or a left edge
+        let top_left = (a.z == b.z && b.x < a.x) || b.z < a.z;
+        if w < 0.0 || (w == 0.0 && !top_left) {
+            return None;
+        }
+        weights[i] = w / total;
+    }
+    Some(weights[0] * t[0].y + weights[1] * t[1].y + weights[2] * t[2].y)
+}
+
+impl Chunk {
+    /// Fills the chunk with the triangles of `mesh`, at the precision of the chunk's `depth`.
+    /// The positions of the mesh are used as they are, in the same units as `Chunk::fill_voxels` takes, so scale and move the mesh first.
+    /// Parts of the mesh outside of the chunk are skipped.
+    pub fn voxelize_mesh(&mut self, thread_pool: ThreadPool, mesh: &Mesh, mode: VoxelizeMode, colors: VoxelizeColors) {
+        let unit_cell_size = self.cell_size();
+        let cell_size = unit_cell_size as f32;
+        let cells_per_axis = CHUNKSIZE/unit_cell_size;
+        let cell_range = |min: f32, max: f32| {
+            let first = (min/cell_size).floor().max(0.0) as u32;
+            let last = ((max/cell_size).floor().max(-1.0) as i64).min(cells_per_axis as i64 - 1);
+            first as i64..=last
+        };
+        let cell_fill_range = |x: u32, y: Vector2<u32>, z: u32| {
+            Vector3::new(Vector2::new(x, x + 1), y, Vector2::new(z, z + 1)).map(|range| range * unit_cell_size)
+        };
+
+        let triangle_color = |i: usize, p: Vector3<f32>| {
+            let indices = mesh.triangles[i].map(|i| i as usize);
+            match colors {
+                VoxelizeColors::Uniform(color) => color,
+                VoxelizeColors::PerTriangle => (mesh.colors[indices[0]] + mesh.colors[indices[1]] + mesh.colors[indices[2]]) / 3.0,
+                VoxelizeColors::PerVertex => {
+                    let w = closest_point_weights(p, indices.map(|i| mesh.positions[i]));
+                    mesh.colors[indices[0]] * w.x + mesh.colors[indices[1]] * w.y + mesh.colors[indices[2]] * w.z
+                },
+            }
+        };
+
+        // The colors are averaged once at the end instead of after every cell
+        if mode == VoxelizeMode::Solid {
+            let mut crossings: HashMap<(u32, u32), Vec<(f32, usize)>> = HashMap::new();
+            for (i, triangle) in mesh.triangles.iter().enumerate() {
+                let t = triangle.map(|i| mesh.positions[i as usize]);
+                let min = t[0].inf(&t[1]).inf(&t[2]);
+                let max = t[0].sup(&t[1]).sup(&t[2]);
+                for x in cell_range(min.x, max.x) {
+                    for z in cell_range(min.z, max.z) {
+                        let center = Vector2::new(x as f32 + 0.5, z as f32 + 0.5) * cell_size;
+                        if let Some(height) = crossing_height(center.x, center.y, t) {
+                            crossings.entry((x as u32, z as u32)).or_default().push((height, i));
+                        }
+                    }
+                }
+            }
+
+            for ((x, z), mut column) in crossings {
+                column.sort_by(|a, b| a.0.total_cmp(&b.0));
+                // Every other crossing enters the mesh, the inside takes the color of the triangle where it was entered
+                for pair in column.chunks_exact(2) {
+                    let (bottom, top) = (pair[0].0, pair[1].0);
+                    let first = ((bottom/cell_size - 0.5).ceil().max(0.0)) as u32;
+                    let end = (((top/cell_size - 0.5).floor() + 1.0).min(cells_per_axis as f32)).max(0.0) as u32;
+                    if first >= end {
+                        continue;
+                    }
+                    let center = Vector3::new(x as f32 + 0.5, first as f32 + 0.5, z as f32 + 0.5) * cell_size;
+                    let range = cell_fill_range(x, Vector2::new(first, end), z);
+                    self.set_voxels_deferred(thread_pool.clone(), range, Some(triangle_color(pair[0].1, center)));
+                }
+            }
+        }
+
+        for (i, triangle) in mesh.triangles.iter().enumerate() {
+            let t = triangle.map(|i| mesh.positions[i as usize]);
+            let min = t[0].inf(&t[1]).inf(&t[2]);
+            let max = t[0].sup(&t[1]).sup(&t[2]);
+            for x in cell_range(min.x, max.x) {
+                for y in cell_range(min.y, max.y) {
+                    for z in cell_range(min.z, max.z) {
+                        let center = Vector3::new(x as f32 + 0.5, y as f32 + 0.5, z as f32 + 0.5) * cell_size;
+                        if triangle_box_overlap(center, cell_size/2.0, t) {
+                            let range = cell_fill_range(x as u32, Vector2::new(y as u32, y as u32 + 1), z as u32);
+                            self.set_voxels_deferred(thread_pool.clone(), range, Some(triangle_color(i, center)));
+                        }
+                    }
+                }
+            }
+        }
+
+        self.recalculate_colors(thread_pool);
+    }
+}
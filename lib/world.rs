@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+
+use nalgebra::{Vector2, Vector3, Vector4};
+
+use crate::threadpool::ThreadPool;
+use crate::voxel::{material::Material, Chunk, Voxel, CHUNKSIZE};
+
+/// A world made of chunks next to each other. A chunk's `position` is its x and z index, so the chunk at `position` covers
+/// `position.x*CHUNKSIZE..(position.x+1)*CHUNKSIZE` along x and the same along z. Along y the world is only one chunk tall.
+#[derive(Debug, Clone)]
+pub struct World {
+    chunks: HashMap<Vector2<i128>, Chunk>,
+    depth: u32,
+}
+
+/// The chunk that world position (`x`, `z`) is in, and the position in that chunk.
+pub fn world_to_chunk(x: i128, z: i128) -> (Vector2<i128>, Vector2<u32>) {
+    let size = CHUNKSIZE as i128;
+    (Vector2::new(x.div_euclid(size), z.div_euclid(size)), Vector2::new(x.rem_euclid(size) as u32, z.rem_euclid(size) as u32))
+}
+
+impl World {
+    /// Creates an empty world, where new chunks get the specified depth.
+    pub fn new(depth: u32) -> World {
+        World { chunks: HashMap::new(), depth }
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    pub fn get_chunk(&self, position: Vector2<i128>) -> Option<&Chunk> {
+        self.chunks.get(&position)
+    }
+
+    pub fn get_chunk_mut(&mut self, position: Vector2<i128>) -> Option<&mut Chunk> {
+        self.chunks.get_mut(&position)
+    }
+
+    /// Adds a chunk at its `position`, returning the chunk that was there before.
+    pub fn insert_chunk(&mut self, chunk: Chunk) -> Option<Chunk> {
+        self.chunks.insert(chunk.position, chunk)
+    }
+
+    pub fn remove_chunk(&mut self, position: Vector2<i128>) -> Option<Chunk> {
+        self.chunks.remove(&position)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.values()
+    }
+
+    /// Fills the voxels in the specified world range, creating the chunks that do not exist yet.
+    /// The ranges go from the first value up to, but not including, the second value. The parts of the range below 0 or above `CHUNKSIZE` along y are skipped.
+    pub fn fill(&mut self, thread_pool: ThreadPool, fill_range: Vector3<Vector2<i128>>, color: Vector4<f32>) {
+        self.set(thread_pool, fill_range, Some(color));
+    }
+
+    /// Fills the voxels in the specified world range with `material`, which is added to the material table of every chunk it ends up in.
+    pub fn fill_material(&mut self, thread_pool: ThreadPool, fill_range: Vector3<Vector2<i128>>, material: Material) {
+        self.edit(fill_range, true, |chunk, range| {
+            let material = chunk.add_material(material);
+            chunk.fill_material(thread_pool.clone(), range, material);
+        });
+    }
+
+    /// Removes the voxels in the specified world range. Chunks that end up empty are removed from the world.
+    pub fn clear(&mut self, thread_pool: ThreadPool, clear_range: Vector3<Vector2<i128>>) {
+        self.set(thread_pool, clear_range, None);
+    }
+
+    /// Fills the voxels in the specified world range with `color`, or removes them if `color` is `None`.
+    pub fn set(&mut self, thread_pool: ThreadPool, range: Vector3<Vector2<i128>>, color: Option<Vector4<f32>>) {
+        self.edit(range, color.is_some(), |chunk, range| chunk.set_voxels(thread_pool.clone(), range, color));
+    }
+
+    /// Calls `edit` with every chunk the world range goes through and the part of the range inside of it.
+    /// If `fills` is true the missing chunks are created first, otherwise they are skipped and the chunks that end up empty are removed.
+    fn edit(&mut self, range: Vector3<Vector2<i128>>, fills: bool, mut edit: impl FnMut(&mut Chunk, Vector3<Vector2<u32>>)) {
+        let size = CHUNKSIZE as i128;
+        let y = Vector2::new(range.y.x.clamp(0, size) as u32, range.y.y.clamp(0, size) as u32);
+        if range.x.x >= range.x.y || y.x >= y.y || range.z.x >= range.z.y {
+            return;
+        }
+
+        let (first, _) = world_to_chunk(range.x.x, range.z.x);
+        let (last, _) = world_to_chunk(range.x.y - 1, range.z.y - 1);
+        for chunk_x in first.x..=last.x {
+            for chunk_z in first.y..=last.y {
+                let position = Vector2::new(chunk_x, chunk_z);
+                // The part of the range that is inside of this chunk, relative to the chunk
+                let local = |range: Vector2<i128>, chunk: i128| Vector2::new((range.x - chunk*size).clamp(0, size) as u32, (range.y - chunk*size).clamp(0, size) as u32);
+                let chunk_range = Vector3::new(local(range.x, chunk_x), y, local(range.z, chunk_z));
+
+                if fills {
+                    let depth = self.depth;
+                    edit(self.chunks.entry(position).or_insert_with(|| Chunk::new(position, depth)), chunk_range);
+                } else {
+                    let Some(chunk) = self.chunks.get_mut(&position) else {
+                        continue;
+                    };
+                    edit(chunk, chunk_range);
+                    // A start voxel without children or a material is an empty chunk
+                    if chunk.start_voxel().is_leaf() && !chunk.start_voxel().is_solid() {
+                        self.chunks.remove(&position);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gets the solid voxel at the given world position, at the precision of the depth of the chunk it is in.
+    /// Returns `None` if that position is empty or outside of the world.
+    pub fn get_voxel(&self, x: i128, y: i128, z: i128) -> Option<&Voxel> {
+        if !(0..CHUNKSIZE as i128).contains(&y) {
+            return None;
+        }
+        let (position, local) = world_to_chunk(x, z);
+        self.chunks.get(&position)?.get_voxel(local.x, y as u32, local.y)
+    }
+}
@@ -0,0 +1,236 @@
+use nalgebra::{Vector2, Vector3, Vector4};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+use crate::threadpool::ThreadPool;
+use crate::voxel::{Chunk, CHUNKSIZE};
+
+/// Ken Perlin's improved noise, from https://mrl.cs.nyu.edu/~perlin/noise/.
+/// The permutation is shuffled from the seed, so the same seed always gives the same noise.
+#[derive(Debug, Clone)]
+pub struct Perlin {
+    permutation: [u8; 512],
+}
+
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f64, a: f64, b: f64) -> f64 {
+    a + t * (b - a)
+}
+
+fn grad(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut values: Vec<u8> = (0..=255).collect();
+        values.shuffle(&mut StdRng::seed_from_u64(seed));
+        let mut permutation = [0_u8; 512];
+        for i in 0..512 {
+            permutation[i] = values[i % 256];
+        }
+        Perlin { permutation }
+    }
+
+    /// Gets the noise at a point, in the range -1 to 1. The noise is 0 at every integer point.
+    pub fn noise(&self, x: f64, y: f64, z: f64) -> f64 {
+        let p = &self.permutation;
+        let (xi, yi, zi) = ((x.floor() as i64 & 255) as usize, (y.floor() as i64 & 255) as usize, (z.floor() as i64 & 255) as usize);
+        let (x, y, z) = (x - x.floor(), y - y.floor(), z - z.floor());
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let a = p[xi] as usize + yi;
+        let (aa, ab) = (p[a] as usize + zi, p[a + 1] as usize + zi);
+        let b = p[xi + 1] as usize + yi;
+        let (ba, bb) = (p[b] as usize + zi, p[b + 1] as usize + zi);
+
+        lerp(w, lerp(v, lerp(u, grad(p[aa], x, y, z), grad(p[ba], x - 1.0, y, z)),
+                        lerp(u, grad(p[ab], x, y - 1.0, z), grad(p[bb], x - 1.0, y - 1.0, z))),
+                lerp(v, lerp(u, grad(p[aa + 1], x, y, z - 1.0), grad(p[ba + 1], x - 1.0, y, z - 1.0)),
+                        lerp(u, grad(p[ab + 1], x, y - 1.0, z - 1.0), grad(p[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    /// Fractal Brownian motion: `octaves` layers of noise, where every layer has `lacunarity` times the frequency and `persistence` times the amplitude
+    /// of the one before. The result is scaled back to the range -1 to 1.
+    pub fn fbm(&self, pos: Vector3<f64>, octaves: u32, lacunarity: f64, persistence: f64) -> f64 {
+        let mut sum = 0.0;
+        let mut max = 0.0;
+        let mut frequency = 1.0;
+        let mut amplitude = 1.0;
+        for _ in 0..octaves {
+            sum += self.noise(pos.x * frequency, pos.y * frequency, pos.z * frequency) * amplitude;
+            max += amplitude;
+            frequency *= lacunarity;
+            amplitude *= persistence;
+        }
+        if max == 0.0 { 0.0 } else { sum / max }
+    }
+}
+
+/// The color of all voxels below `below`, and above the band before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorBand {
+    pub below: f64,
+    pub color: Vector4<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CaveSettings {
+    /// How many times the cave noise repeats per unit.
+    pub frequency: f64,
+    pub octaves: u32,
+    /// Voxels where the cave noise is above this are carved out, so higher values give fewer caves.
+    pub threshold: f64,
+}
+
+/// Generates terrain from noise in world space, so that chunks generated next to each other line up without seams.
+/// The world position of a voxel is the chunk's `position` times `CHUNKSIZE` plus its position in the chunk, where `position` is the x and z index of the chunk.
+#[derive(Debug, Clone)]
+pub struct WorldGenerator {
+    seed: u64,
+    noise: Perlin,
+    /// The height of the terrain where the height noise is 0, in units.
+    pub base_height: f64,
+    /// How far the terrain goes above and below `base_height`, in units.
+    pub height_amplitude: f64,
+    /// How many times the height noise repeats per unit.
+    pub height_frequency: f64,
+    pub octaves: u32,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    /// Sorted from the lowest to the highest band. Voxels above the last band get the color of the last band.
+    pub color_bands: Vec<ColorBand>,
+    pub caves: Option<CaveSettings>,
+}
+
+impl WorldGenerator {
+    /// Creates a generator with rolling hills, sand at the bottom, then grass, rock and snow at the top, and caves.
+    pub fn new(seed: u64) -> WorldGenerator {
+        let size = CHUNKSIZE as f64;
+        WorldGenerator {
+            seed,
+            noise: Perlin::new(seed),
+            base_height: size/4.0,
+            height_amplitude: size/8.0,
+            height_frequency: 1.0/(size/4.0),
+            octaves: 5,
+            lacunarity: 2.0,
+            persistence: 0.5,
+            color_bands: vec![
+                ColorBand { below: size * 0.2, color: Vector4::new(0.85, 0.8, 0.55, 1.0) },
+                ColorBand { below: size * 0.3, color: Vector4::new(0.25, 0.6, 0.2, 1.0) },
+                ColorBand { below: size * 0.34, color: Vector4::new(0.45, 0.45, 0.45, 1.0) },
+                ColorBand { below: f64::INFINITY, color: Vector4::new(0.95, 0.95, 0.95, 1.0) },
+            ],
+            caves: Some(CaveSettings { frequency: 1.0/(size/32.0), octaves: 2, threshold: 0.3 }),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Gets the height of the terrain surface at a world position, in units.
+    pub fn height_at(&self, x: f64, z: f64) -> f64 {
+        // The height noise is taken from a plane between the integer points, where Perlin noise is not always 0
+        let pos = Vector3::new(x * self.height_frequency, 0.5, z * self.height_frequency);
+        self.base_height + self.noise.fbm(pos, self.octaves, self.lacunarity, self.persistence) * self.height_amplitude
+    }
+
+    /// Checks if a world position is carved out by a cave.
+    pub fn is_cave(&self, pos: Vector3<f64>) -> bool {
+        match self.caves {
+            // Offset from the height noise, so that the caves do not follow the surface
+            Some(caves) => self.noise.fbm(pos * caves.frequency + Vector3::new(0.0, 0.0, 1000.5), caves.octaves, self.lacunarity, self.persistence) > caves.threshold,
+            None => false,
+        }
+    }
+
+    pub fn color_at(&self, height: f64) -> Vector4<f32> {
+        self.color_bands.iter()
+            .find(|band| height < band.below)
+            .or(self.color_bands.last())
+            .map_or(Vector4::new(1.0, 1.0, 1.0, 1.0), |band| band.color)
+    }
+
+    /// Creates a chunk at `position` and fills it with terrain.
+    pub fn generate_chunk(&self, thread_pool: ThreadPool, position: Vector2<i128>, depth: u32) -> Chunk {
+        let mut chunk = Chunk::new(position, depth);
+        self.fill_chunk(thread_pool, &mut chunk);
+        chunk
+    }
+
+    /// Fills a chunk with terrain at the precision of the chunk's `depth`, every column of cells is sampled at its center.
+    /// The time this takes grows with the number of cells, so deep chunks take long to generate.
+    pub fn fill_chunk(&self, thread_pool: ThreadPool, chunk: &mut Chunk) {
+        let cell_size = chunk.cell_size();
+        let cells_per_axis = CHUNKSIZE/cell_size;
+        let origin = Vector2::new(chunk.position.x as f64, chunk.position.y as f64) * CHUNKSIZE as f64;
+        let cell_center = |cell: u32| (cell as f64 + 0.5) * cell_size as f64;
+
+        // Every row of columns along z is sampled in its own job, and the rows are put back together in order so that the result does not depend on the threads
+        type Column = (u32, u32, Vector2<u32>, Vector4<f32>);
+        let rows: Vec<Vec<Column>> = thread_pool.scope(|scope| {
+            let handles: Vec<_> = (0..cells_per_axis).map(|x| scope.spawn(move || {
+                let mut columns = vec![];
+                for z in 0..cells_per_axis {
+                    let (world_x, world_z) = (origin.x + cell_center(x), origin.y + cell_center(z));
+                    let height = (self.height_at(world_x, world_z) / cell_size as f64).round().clamp(0.0, cells_per_axis as f64) as u32;
+
+                    // Cells next to each other with the same color are filled together
+                    let mut run: Option<(u32, Vector4<f32>)> = None;
+                    for y in 0..=height {
+                        let solid = y < height && !self.is_cave(Vector3::new(world_x, cell_center(y), world_z));
+                        let color = self.color_at(cell_center(y));
+                        match run {
+                            Some((start, run_color)) if !solid || run_color != color => {
+                                columns.push((x, z, Vector2::new(start, y), run_color));
+                                run = if solid { Some((y, color)) } else { None };
+                            },
+                            None if solid => run = Some((y, color)),
+                            _ => (),
+                        }
+                    }
+                }
+                columns
+            })).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        // The colors are averaged once at the end instead of after every column
+        for (x, z, y, color) in rows.into_iter().flatten() {
+            let range = Vector3::new(Vector2::new(x, x + 1), y, Vector2::new(z, z + 1)).map(|range| range * cell_size);
+            chunk.set_voxels_deferred(thread_pool.clone(), range, Some(color));
+        }
+        chunk.recalculate_colors(thread_pool);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_chunk() {
+        let thread_pool = ThreadPool::new(Some(4));
+        let position = Vector2::new(3, -2);
+        let chunk = WorldGenerator::new(4230).generate_chunk(thread_pool.clone(), position, 5);
+        assert!(!chunk.start_voxel().is_leaf());
+        assert_eq!(WorldGenerator::new(4230).generate_chunk(thread_pool.clone(), position, 5), chunk);
+        assert_ne!(WorldGenerator::new(4231).generate_chunk(thread_pool, position, 5), chunk);
+    }
+
+    #[test]
+    fn threads_do_not_change_the_chunk() {
+        let generator = WorldGenerator::new(7);
+        let position = Vector2::new(-1, 5);
+        let one_thread = generator.generate_chunk(ThreadPool::new(Some(1)), position, 5);
+        let many_threads = generator.generate_chunk(ThreadPool::new(Some(8)), position, 5);
+        assert_eq!(one_thread, many_threads);
+    }
+}
@@ -0,0 +1,221 @@
+// The parts of the ray tracer that are the same for every layout of the oct tree data. It is included by the shader for each layout,
+// which defines the functions below that look at the nodes of that layout.
+// CHUNKSIZE and MAX_DEPTH are defined when the shaders are compiled, from CHUNKPOWER.
+layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
+
+struct MaterialData
+{
+    vec2 base_color_rg;
+    // The opacity is the base_color_ba.y value
+    vec2 base_color_ba;
+    vec2 roughness_metalness;
+    vec2 emission;
+};
+
+layout(set = 0, binding = 1)  readonly buffer CameraData {
+    uint field_of_view;
+    float render_distance;
+    float aspectRatio;
+    float fov_tan;
+    mat4 camera_to_world;
+    vec4 clear_color;
+} camera;
+
+layout(set = 0, binding = 2) readonly buffer Materials {
+    MaterialData data[];
+} material_data;
+
+layout(set = 1, binding = 0, rgba8) uniform image2D img_out;
+
+// The G-buffer, with the distance to the hit, the normal of the face that was hit and the node that was hit for every pixel.
+// It is only there when the shader is compiled with G_BUFFER defined, so that the window does not have to bind one
+#ifdef G_BUFFER
+layout(set = 2, binding = 0, r32f) uniform image2D depth_out;
+layout(set = 2, binding = 1, rgba8_snorm) uniform image2D normal_out;
+layout(set = 2, binding = 2, r32ui) uniform uimage2D node_out;
+#endif
+
+struct ColorHit {
+    bool hit;
+    vec4 color;
+    // UINT_MAX for voxels that only have an averaged color
+    uint material_index;
+    vec3 normal;
+    // The distance along the ray to where it enters the voxel, 0 if it starts inside of it
+    float t;
+    // Where the node is in the oct tree data, in the way the layout refers to its nodes
+    uint node;
+};
+
+struct Ray {
+    vec3 origin;
+    vec3 direction;
+};
+
+// A node on the way down to the voxel that is being looked at, and the next of its children to look at
+struct StackEntry {
+    uint node;
+    vec3 pos;
+    float size;
+    uint next_child;
+};
+
+// Const variables
+const uint UINT_MAX = -1;
+const float INFINITY_F = 1.0/0.0;
+const vec3 LIGHT_DIRECTION = normalize(vec3(0.4, 1.0, 0.3));
+
+// The functions each layout defines
+
+// Where the start voxel is
+uint start_node();
+// Where child i of the node is, or UINT_MAX if it does not have that child
+uint child_node(uint node, uint i);
+bool is_leaf_node(uint node);
+vec4 node_color(uint node);
+// UINT_MAX for nodes without a material
+uint node_material(uint node);
+
+// Helper functions
+
+float max_component(vec3 vec) {
+    return max(max(vec.x, vec.y), vec.z);
+}
+
+float min_component(vec3 vec) {
+    return min(min(vec.x, vec.y), vec.z);
+}
+
+// From https://jcgt.org/published/0007/03/04/, it also gives the distance to where the ray enters the voxel and the normal of the side it
+// enters through. A ray that starts inside of the voxel enters it at 0 and gets a zero normal, like on the CPU side
+bool slabs(vec3 p0, float size, Ray ray, vec3 invRaydir, out float t_enter, out vec3 normal) {
+    const vec3 t0 = (p0 - ray.origin) * invRaydir;
+    const vec3 t1 = (p0 + vec3(size) - ray.origin) * invRaydir;
+    const vec3 tmin = min(t0,t1), tmax = max(t0,t1);
+    const float tmax_val = min_component(tmax);
+    const float tmin_val = max_component(tmin);
+    t_enter = max(tmin_val, 0.0);
+    // The ray enters through the side on the first axis where it enters the slab last, so that a ray through an edge gets one normal
+    const int axis = tmin.x >= tmin.y && tmin.x >= tmin.z ? 0 : (tmin.y >= tmin.z ? 1 : 2);
+    normal = vec3(0.0);
+    if (tmin_val > 0.0) normal[axis] = -sign(ray.direction[axis]);
+    return tmin_val <= tmax_val && tmax_val >= 0.0;
+}
+
+vec3 child_offset(uint i) {
+    return vec3(i % 2, i / 4, (i / 2) % 2);
+}
+
+// Child i of a voxel is child i ^ octant_mask for a ray going towards the negative side on some axes.
+// Visiting them in that order is front to back, since the ray can only go from a child to one with a higher mirrored index.
+uint octant_mask(vec3 direction) {
+    return (direction.x < 0.0 ? 1u : 0u) | (direction.z < 0.0 ? 2u : 0u) | (direction.y < 0.0 ? 4u : 0u);
+}
+
+ColorHit fill_hit_color(uint node, float t, vec3 normal) {
+    ColorHit data;
+    data.hit = true;
+    data.color = node_color(node);
+    data.material_index = node_material(node);
+    data.normal = normal;
+    data.t = t;
+    data.node = node;
+    return data;
+}
+
+// Plain materials (roughness 1, metalness 0 and no emission) keep their color as it is.
+// Smoother materials get a highlight from the light, tinted by the base color for metals, and emission makes the color brighter.
+vec4 shade_hit(ColorHit hit, Ray ray) {
+    if (hit.material_index == UINT_MAX) return hit.color;
+    const MaterialData material = material_data.data[hit.material_index];
+    const vec3 base_color = vec3(material.base_color_rg, material.base_color_ba.x);
+    const float roughness = material.roughness_metalness.x;
+    const float metalness = material.roughness_metalness.y;
+
+    const float shininess = mix(256.0, 2.0, roughness);
+    const float highlight = (1.0 - roughness) * pow(max(dot(reflect(-LIGHT_DIRECTION, hit.normal), -ray.direction), 0.0), shininess);
+    const vec3 highlight_color = mix(vec3(1.0), base_color, metalness);
+    // Metals show most of their color in the highlight, so the rest of the surface is darker
+    const vec3 surface_color = base_color * mix(1.0, 0.5, metalness);
+
+    return vec4(surface_color + highlight_color * highlight + base_color * material.emission.x, material.base_color_ba.y);
+}
+
+ColorHit voxel_hit(Ray ray, vec4 clear_col) {
+    ColorHit ret_val;
+    ret_val.color = clear_col;
+    ret_val.hit = false;
+    ret_val.material_index = UINT_MAX;
+    ret_val.normal = vec3(0.0);
+    ret_val.t = INFINITY_F;
+    ret_val.node = UINT_MAX;
+    const vec3 invRaydir = 1.0/ray.direction;
+    const uint mirror = octant_mask(ray.direction);
+    float t_enter;
+    vec3 normal;
+
+    // Look here for tip on how to find the intersection/hit point: https://tavianator.com/2011/ray_box.html
+    const uint start = start_node();
+    if (!slabs(vec3(0.0), float(CHUNKSIZE), ray, invRaydir, t_enter, normal)) return ret_val;
+    // A start voxel without children is one solid voxel if it has a material, and an empty chunk if it does not
+    if (is_leaf_node(start)) return node_material(start) != UINT_MAX ? fill_hit_color(start, t_enter, normal) : ret_val;
+
+    // GLSL does not allow for recursive functions, so the way down is kept on a stack with room for every level of the tree
+    StackEntry stack[MAX_DEPTH + 1];
+    int top = 0;
+    stack[0] = StackEntry(start, vec3(0.0), float(CHUNKSIZE), 0u);
+    while (top >= 0) {
+        const StackEntry entry = stack[top];
+        if (entry.next_child == 8) {
+            top--;
+            continue;
+        }
+        stack[top].next_child++;
+
+        const uint i = entry.next_child ^ mirror;
+        const uint child = child_node(entry.node, i);
+        if (child == UINT_MAX) continue;
+        const float size = entry.size / 2.0;
+        const vec3 pos = entry.pos + child_offset(i) * size;
+        if (!slabs(pos, size, ray, invRaydir, t_enter, normal)) continue;
+
+        // The children are visited front to back, so the first leaf the ray hits is the closest one
+        if (is_leaf_node(child)) return fill_hit_color(child, t_enter, normal);
+        top++;
+        stack[top] = StackEntry(child, pos, size, 0u);
+    }
+
+    return ret_val;
+}
+
+// Writes what the ray hit to the G-buffer, if the shader has one
+void write_g_buffer(ivec2 IDxy, ColorHit hit) {
+#ifdef G_BUFFER
+    imageStore(depth_out, IDxy, vec4(max(hit.t, 0.0)));
+    imageStore(normal_out, IDxy, vec4(hit.normal, 0.0));
+    imageStore(node_out, IDxy, uvec4(hit.node));
+#endif
+}
+
+
+// Main
+void main() {
+    ivec2 IDxy = ivec2(gl_GlobalInvocationID.xy);
+
+    const ivec2 screenSize = imageSize(img_out);
+    const vec2 pixel_NCD = vec2((float(IDxy.x)+0.5)/float(screenSize.x), (float(IDxy.y)+0.5)/float(screenSize.y));
+    const vec2 camera_pixel = vec2((2 * pixel_NCD.x - 1) * camera.aspectRatio * camera.fov_tan, (1 - 2 * pixel_NCD.y) * camera.fov_tan);
+
+    const highp vec4 world_search_pos = vec4(vec3(camera_pixel.x, camera_pixel.y, -1.0), 0.0)*camera.camera_to_world;
+    highp vec3 current_search_pos = normalize(world_search_pos.xyz);
+    current_search_pos.x = -current_search_pos.x;
+    vec4 color_in_the_end = camera.clear_color;
+
+    const Ray ray = Ray(vec3(camera.camera_to_world[3].x, camera.camera_to_world[3].y, camera.camera_to_world[3].z), normalize(current_search_pos));
+
+    ColorHit check = voxel_hit(ray, camera.clear_color);
+    if (check.hit) color_in_the_end = shade_hit(check, ray);
+    write_g_buffer(IDxy, check);
+
+    imageStore(img_out, IDxy, vec4(color_in_the_end.b, color_in_the_end.g, color_in_the_end.r, color_in_the_end.a));
+}
@@ -1,25 +1,79 @@
+use std::f32::consts::PI;
+use std::path::Path;
+
 use nalgebra::{Vector2, Vector3, Vector4};
-use renderer::setup_renderer_and_run;
+use voxel::material::Material;
+use voxel::frustum::Frustum;
+use renderer::{setup_renderer_and_run, OctTreeData};
+use renderer::headless::{render_headless, CameraPose};
 use voxel::{Chunk};
-use artewald_engine_lib::threadpool::ThreadPoolHelper;
+use artewald_engine_lib::threadpool::ThreadPool;
 use artewald_engine_lib::voxel;
 
 mod renderer;
 
+/// How many pixels a voxel can cover on the screen before its children are sent to the GPU.
+const LOD_MAX_ERROR: f32 = 1.0;
+/// Prints how many voxels the frustum culling left out every time the oct tree is built.
+const PRINT_CULL_INFO: bool = false;
+/// Sends the oct tree to the GPU in the compact layout instead of the full one, see `CompactVoxelData`.
+const COMPACT_OCT_TREE: bool = false;
+/// The size of the frames in the headless mode.
+const HEADLESS_WIDTH: u32 = 640;
+const HEADLESS_HEIGHT: u32 = 480;
+
+/// Builds the oct tree the GPU gets for a camera at `camera_pos` with `frustum`.
+fn build_oct_tree(chunk: &Chunk, camera_pos: Vector3<f64>, frustum: &Frustum) -> OctTreeData {
+    let pixel_rad = (90.0 as f32/1080.0 as f32).to_radians();
+    let (len, culled, oct_tree) = if COMPACT_OCT_TREE {
+        let (voxel_data, culled) = chunk.get_compact_oct_tree_culled(camera_pos, pixel_rad, LOD_MAX_ERROR, frustum);
+        (voxel_data.len(), culled, OctTreeData::Compact(voxel_data))
+    } else {
+        let (voxel_data, culled) = chunk.get_oct_tree_culled(camera_pos, pixel_rad, LOD_MAX_ERROR, frustum);
+        (voxel_data.len(), culled, OctTreeData::Full(voxel_data))
+    };
+    if PRINT_CULL_INFO {
+        println!("Sending {} voxels to the GPU, {} were culled", len, culled);
+    }
+    if len > (u32::MAX-2) as usize {
+        panic!("There are more than u32-2 indices in the voxel array for the gpu, that's too much for the GPU");
+    }
+    oct_tree
+}
+
+/// A camera going once around the voxels in `frames` steps, for the headless mode.
+fn orbit(frames: usize) -> Vec<CameraPose> {
+    let center = Vector3::new(8.0, 2.0, 8.0);
+    (0..frames).map(|frame| {
+        let angle = frame as f32/frames as f32 * 2.0 * PI;
+        CameraPose { position: center + Vector3::new(angle.cos() * 20.0, 12.0, angle.sin() * 20.0), look_at: center }
+    }).collect()
+}
+
 // Optimization can be done by using flamegraph and cargo-asm
+// Run with `--headless [frames] [output directory]` to render frames to files instead of opening a window,
+// and add `--g-buffer` to write the depth, the normals and the hit nodes of the frames as well
 fn main() {
     //let time = Instant::now();
-    let thread_pool = ThreadPoolHelper::new(Some(0));
+    let thread_pool = ThreadPool::new(None);
     let mut chunk = Chunk::new(Vector2::new(0, 0), 16);
     chunk.fill_voxels(thread_pool.clone(), Vector3::new(Vector2::new(5, 10), Vector2::new(0, 5), Vector2::new(3, 15)), Vector4::new(1.0, 0.0, 0.0, 1.0));
     chunk.fill_voxels(thread_pool.clone(), Vector3::new(Vector2::new(0, 3), Vector2::new(2, 5), Vector2::new(5, 10)), Vector4::new(0.0, 1.0, 0.0, 1.0));
-    let voxel_data = chunk.get_oct_tree(Vector3::new(0.0, 0.0, 0.0), (90.0 as f32/1080.0 as f32).to_radians());
-    if voxel_data.len() > (u32::MAX-2) as usize {
-        panic!("There are more than u32-2 indices in the voxel array for the gpu, that's too much for the GPU");
+    let metal = chunk.add_material(Material { base_color: Vector3::new(0.8, 0.8, 0.85), roughness: 0.15, metalness: 1.0, emission: 0.0, opacity: 1.0 });
+    let lava = chunk.add_material(Material { base_color: Vector3::new(1.0, 0.3, 0.05), roughness: 0.9, metalness: 0.0, emission: 2.0, opacity: 1.0 });
+    chunk.fill_material(thread_pool.clone(), Vector3::new(Vector2::new(11, 14), Vector2::new(0, 3), Vector2::new(3, 6)), metal);
+    chunk.fill_material(thread_pool.clone(), Vector3::new(Vector2::new(0, 5), Vector2::new(0, 1), Vector2::new(11, 15)), lava);
+
+    let args: Vec<String> = std::env::args().collect();
+    let g_buffer = args.iter().any(|arg| arg == "--g-buffer");
+    let args: Vec<&str> = args.iter().map(String::as_str).filter(|arg| *arg != "--g-buffer").collect();
+    if args.get(1) == Some(&"--headless") {
+        let frames = args.get(2).map(|frames| frames.parse().expect("The number of frames has to be a number")).unwrap_or(8);
+        let output_dir = Path::new(args.get(3).unwrap_or(&"frames"));
+        render_headless(thread_pool, &chunk, build_oct_tree, &orbit(frames), HEADLESS_WIDTH, HEADLESS_HEIGHT, output_dir, g_buffer).expect("Failed to write the frames");
+        return;
     }
-    // for voxel in voxel_data {
-    //     println!("{:?}\t{:?}\t{:?}\t{:?}\n", voxel.pos, voxel.range, voxel.color_rg, voxel.color_ba);
-    // }
-    // println!("{:?}", voxel_data);
-    setup_renderer_and_run(voxel_data);
+
+    let material_data = chunk.materials().get_material_data();
+    setup_renderer_and_run(move |camera_pos, frustum| build_oct_tree(&chunk, camera_pos, frustum), material_data);
 }
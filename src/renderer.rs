@@ -1,19 +1,58 @@
-use std::{time::Instant, f32::consts::PI};
+use std::{mem::discriminant, time::Instant, f32::consts::PI, sync::Arc};
 
 use nalgebra::{Vector4, Vector3};
-use utils::{setup_vulkan, create_main_shader, create_sets, create_render_image};
-use vulkano::{pipeline::{ComputePipeline, Pipeline, PipelineBindPoint}, command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo, CopyImageInfo, CopyBufferToImageInfo}, sync::{self, GpuFuture, FlushError}, image::{ImageAccess}, swapchain::{self, acquire_next_image, AcquireError, SwapchainPresentInfo}};
+use utils::{setup_vulkan, create_main_shader, create_compact_shader, create_dag_shader, create_sets, create_render_image};
+use vulkano::{device::Device, pipeline::{ComputePipeline, Pipeline, PipelineBindPoint}, command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo, CopyImageInfo, CopyBufferToImageInfo}, sync::{self, GpuFuture, FlushError}, image::{ImageAccess}, swapchain::{self, acquire_next_image, AcquireError, SwapchainPresentInfo}};
 use winit::{event_loop::{EventLoop, ControlFlow}, event::{Event, WindowEvent, VirtualKeyCode, ElementState}, dpi::PhysicalPosition};
 
-use crate::voxel::{VoxelData};
+use crate::voxel::{VoxelData, compact::CompactVoxelData, dag::VoxelDag, frustum::Frustum, material::MaterialData};
 
-use self::utils::{create_voxel_buffer, create_camera_data_buffer, CameraData};
+use self::utils::{create_oct_tree_buffer, create_material_buffer, create_camera_data_buffer, CameraData};
 
+pub mod headless;
 mod utils;
 
 const PRINT_RENDER_INFO: bool = false;
-
-pub fn setup_renderer_and_run(voxel_data: Vec<VoxelData>) {
+/// The vertical field of view of the camera in degrees.
+const FIELD_OF_VIEW: u32 = 90;
+const RENDER_DISTANCE: f32 = 1000.0;
+/// The color of the pixels where nothing is hit.
+const CLEAR_COLOR: Vector4<f32> = Vector4::new(0.0, 0.0, 0.1884, 1.0);
+/// How far the camera can move before the oct tree is built again, with the level of detail for where the camera is now.
+const LOD_REBUILD_DISTANCE: f32 = 1.0;
+/// How many degrees wider the frustum used for culling is than the one of the camera, on every side.
+/// The oct tree is built again when the camera has turned half of this, since voxels near the corners of the screen move less than the camera turns.
+const CULL_MARGIN_ANGLE: f32 = 20.0;
+
+/// The voxels to render, in one of the layouts there is a shader for.
+pub enum OctTreeData {
+    Full(Vec<VoxelData>),
+    Compact(Vec<CompactVoxelData>),
+    Dag(VoxelDag),
+}
+
+/// Creates the compute pipeline with the shader for the layout of `oct_tree`, with a G-buffer in set 2 if `g_buffer` is set.
+fn create_compute_pipeline(device: Arc<Device>, oct_tree: &OctTreeData, g_buffer: bool) -> Arc<ComputePipeline> {
+    let shader = match oct_tree {
+        OctTreeData::Full(_) => create_main_shader(device.clone(), g_buffer),
+        OctTreeData::Compact(_) => create_compact_shader(device.clone(), g_buffer),
+        OctTreeData::Dag(_) => create_dag_shader(device.clone(), g_buffer),
+    };
+    ComputePipeline::new(
+        device, 
+        shader.entry_point("main").unwrap(), 
+        &(), 
+        None, 
+        |_| {}
+    ).unwrap()
+}
+
+/// Opens a window and renders the voxels from `build_oct_tree`, which builds the oct tree for a camera position and the frustum of the camera.
+/// The oct tree is built again every time the camera has moved `LOD_REBUILD_DISTANCE`, so that the level of detail follows the camera,
+/// and every time it has turned half of `CULL_MARGIN_ANGLE`, so that voxels culled with the frustum can be seen again.
+/// # Panics
+/// The function panics if `build_oct_tree` does not always give the same layout, since the shader is picked for the first one.
+pub fn setup_renderer_and_run(mut build_oct_tree: impl FnMut(Vector3<f64>, &Frustum) -> OctTreeData + 'static, material_data: Vec<MaterialData>) {
     // Settings
 
     // Setup window and device
@@ -21,24 +60,25 @@ pub fn setup_renderer_and_run(voxel_data: Vec<VoxelData>) {
     
     let mut vulkan_data = setup_vulkan(&event_loop);
 
-    // Setup shaders, pipeline and buffers and descriptor sets
-    let main_shader = create_main_shader(vulkan_data.device.clone());
-    let compute_pipline = ComputePipeline::new(
-        vulkan_data.device.clone(), 
-        main_shader.entry_point("main").unwrap(), 
-        &(), 
-        None, 
-        |_| {}
-    ).unwrap();
+    let camera_data_buffer = create_camera_data_buffer(CameraData::new(FIELD_OF_VIEW, RENDER_DISTANCE, (vulkan_data.window.inner_size().width as f32)/(vulkan_data.window.inner_size().height as f32), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0), CLEAR_COLOR, Vector3::new(-2.0, 0.0, 0.0)), vulkan_data.allocator.clone());
 
-    let camera_data_buffer = create_camera_data_buffer(CameraData::new(90, 1000.0, (vulkan_data.window.inner_size().width as f32)/(vulkan_data.window.inner_size().height as f32), Vector3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0), Vector4::new(0.0, 0.0, 0.1884, 1.0), Vector3::new(-2.0, 0.0, 0.0)), vulkan_data.allocator.clone());
-    let voxel_buffer = create_voxel_buffer(voxel_data, vulkan_data.allocator.clone());
+    // Setup shaders, pipeline and buffers and descriptor sets
+    let mut lod_position = camera_data_buffer.read().unwrap().position;
+    let mut lod_target = Vector3::new(0.0, 0.0, 1.0);
+    let mut lod_aspect_ratio = camera_data_buffer.read().unwrap().aspect_ratio;
+    let oct_tree = build_oct_tree(lod_position.cast(), &camera_data_buffer.read().unwrap().frustum(CULL_MARGIN_ANGLE));
+    let layout = discriminant(&oct_tree);
+    // The window only shows the colors, so the shader is created without a G-buffer
+    let compute_pipline = create_compute_pipeline(vulkan_data.device.clone(), &oct_tree, false);
+    let mut voxel_buffer = create_oct_tree_buffer(oct_tree, vulkan_data.allocator.clone());
+
+    let material_buffer = create_material_buffer(material_data, vulkan_data.allocator.clone());
     let mut render_image_data = create_render_image(&mut vulkan_data);
 
     let compute_pipeline_clone = compute_pipline.clone();
     let set_layouts = compute_pipeline_clone.layout().set_layouts();
 
-    let mut sets = create_sets(vulkan_data.desc_allocator.clone(), set_layouts, voxel_buffer.clone(), camera_data_buffer.clone(), render_image_data.view.clone());
+    let mut sets = create_sets(vulkan_data.desc_allocator.clone(), set_layouts, voxel_buffer.clone(), camera_data_buffer.clone(), material_buffer.clone(), render_image_data.view.clone(), None);
 
 
     // Main render loop
@@ -164,7 +204,7 @@ pub fn setup_renderer_and_run(voxel_data: Vec<VoxelData>) {
                     let compute_pipeline_cpy = compute_pipline.clone();
                     let new_set_layouts = compute_pipeline_cpy.layout().set_layouts();
                     camera_data_buffer.clone().write().unwrap().aspect_ratio = (dim.width as f32)/(dim.height as f32);
-                    sets = create_sets(vulkan_data.desc_allocator.clone(), new_set_layouts, voxel_buffer.clone(), camera_data_buffer.clone(), render_image_data.view.clone());
+                    sets = create_sets(vulkan_data.desc_allocator.clone(), new_set_layouts, voxel_buffer.clone(), camera_data_buffer.clone(), material_buffer.clone(), render_image_data.view.clone(), None);
                     recreate_swapchain = false;
                 }
 
@@ -187,6 +227,24 @@ pub fn setup_renderer_and_run(voxel_data: Vec<VoxelData>) {
                 camera_data_buffer.clone().write().unwrap().camera_move(movement_input, look_target, delta_time.elapsed().as_millis() as f32, movement_speed);
                 delta_time = Instant::now();
                 camera_data_buffer.clone().write().unwrap().update_camera_dir(look_target, Vector3::new(0.0, 1.0, 0.0));
+
+                // Rebuilds the oct tree when the camera has moved far enough for the level of detail to change, or turned far enough for the culled voxels to be seen
+                let camera_position = camera_data_buffer.read().unwrap().position;
+                let aspect_ratio = camera_data_buffer.read().unwrap().aspect_ratio;
+                if (camera_position - lod_position).norm() >= LOD_REBUILD_DISTANCE || look_target.angle(&lod_target) >= (CULL_MARGIN_ANGLE/2.0).to_radians() || aspect_ratio != lod_aspect_ratio {
+                    let frustum = camera_data_buffer.read().unwrap().frustum(CULL_MARGIN_ANGLE);
+                    let oct_tree = build_oct_tree(camera_position.cast(), &frustum);
+                    if discriminant(&oct_tree) != layout {
+                        panic!("The oct tree layout can not change while rendering");
+                    }
+                    voxel_buffer = create_oct_tree_buffer(oct_tree, vulkan_data.allocator.clone());
+                    let compute_pipeline_cpy = compute_pipline.clone();
+                    let new_set_layouts = compute_pipeline_cpy.layout().set_layouts();
+                    sets = create_sets(vulkan_data.desc_allocator.clone(), new_set_layouts, voxel_buffer.clone(), camera_data_buffer.clone(), material_buffer.clone(), render_image_data.view.clone(), None);
+                    lod_position = camera_position;
+                    lod_target = look_target;
+                    lod_aspect_ratio = aspect_ratio;
+                }
                 
                 let mut builder = AutoCommandBufferBuilder::primary(vulkan_data.cmd_allocator.clone().as_ref(), vulkan_data.queue.queue_family_index(), CommandBufferUsage::OneTimeSubmit,).unwrap();
                 builder.bind_pipeline_compute(compute_pipline.clone())
@@ -0,0 +1,129 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, mem::discriminant, path::Path};
+
+use artewald_engine_lib::threadpool::ThreadPool;
+use nalgebra::Vector3;
+use vulkano::{pipeline::{Pipeline, PipelineBindPoint}, command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, CopyImageToBufferInfo}, sync::{self, GpuFuture}};
+
+use crate::voxel::{Chunk, frustum::Frustum, render::{GBuffer, RgbaImage}};
+
+use super::{create_compute_pipeline, OctTreeData, FIELD_OF_VIEW, RENDER_DISTANCE, CLEAR_COLOR};
+use super::utils::{setup_headless_vulkan, create_camera_data_buffer, create_material_buffer, create_oct_tree_buffer, create_sets, create_storage_image, create_g_buffer, CameraData, HeadlessVulkanData};
+
+/// Where the camera is and what it looks at for one frame rendered by `render_headless`.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraPose {
+    pub position: Vector3<f32>,
+    pub look_at: Vector3<f32>,
+}
+
+impl CameraPose {
+    fn camera_data(&self, aspect_ratio: f32) -> CameraData {
+        // The rays go in the direction of the camera target with the x axis flipped, see main() in the compute shaders
+        let direction = self.look_at - self.position;
+        let target = Vector3::new(-direction.x, direction.y, direction.z);
+        CameraData::new(FIELD_OF_VIEW, RENDER_DISTANCE, aspect_ratio, target, Vector3::new(0.0, 1.0, 0.0), CLEAR_COLOR, self.position)
+    }
+}
+
+fn write_frame(output_dir: &Path, frame: usize, image: &RgbaImage) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("frame_{:04}.ppm", frame)))?);
+    image.write_ppm(&mut writer)?;
+    writer.flush()
+}
+
+fn write_g_buffer(output_dir: &Path, frame: usize, g_buffer: &GBuffer) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("depth_{:04}.pfm", frame)))?);
+    g_buffer.write_depth_pfm(&mut writer)?;
+    writer.flush()?;
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("normal_{:04}.ppm", frame)))?);
+    g_buffer.write_normal_ppm(&mut writer)?;
+    writer.flush()?;
+    let mut writer = BufWriter::new(File::create(output_dir.join(format!("nodes_{:04}.bin", frame)))?);
+    g_buffer.write_nodes(&mut writer)?;
+    writer.flush()
+}
+
+/// Renders one frame for every pose in `frames` without opening a window, and writes them to `output_dir` as `frame_0000.ppm`, `frame_0001.ppm` and so on.
+/// The frames are rendered with the compute shaders if there is a Vulkan device, with the oct tree from `build_oct_tree` like in `setup_renderer_and_run`.
+/// Without a Vulkan device they are rendered on the CPU with `Chunk::render` instead.
+/// With `g_buffer` the depth, the normals and the nodes that were hit are written as well, to `depth_0000.pfm`, `normal_0000.ppm` and `nodes_0000.bin`
+/// and so on, see `GBuffer`.
+/// # Panics
+/// The function panics if `width` or `height` is not a multiple of 8, since the compute shaders work on 8 by 8 pixels at a time,
+/// or if `build_oct_tree` does not always give the same layout.
+#[allow(clippy::too_many_arguments)]
+pub fn render_headless(thread_pool: ThreadPool, chunk: &Chunk, build_oct_tree: impl FnMut(&Chunk, Vector3<f64>, &Frustum) -> OctTreeData, frames: &[CameraPose], width: u32, height: u32, output_dir: &Path, g_buffer: bool) -> io::Result<()> {
+    if !width.is_multiple_of(8) || !height.is_multiple_of(8) {
+        panic!("render_headless(): The width and height have to be multiples of 8");
+    }
+    fs::create_dir_all(output_dir)?;
+    let cameras: Vec<CameraData> = frames.iter().map(|pose| pose.camera_data(width as f32/height as f32)).collect();
+
+    match setup_headless_vulkan() {
+        Some(vulkan_data) => render_on_gpu(&vulkan_data, chunk, build_oct_tree, &cameras, width, height, output_dir, g_buffer),
+        None => {
+            println!("No Vulkan device was found, rendering on the CPU");
+            for (i, camera) in cameras.iter().enumerate() {
+                let (image, frame_g_buffer) = chunk.render_with_g_buffer(thread_pool.clone(), &camera.camera(), width, height);
+                write_frame(output_dir, i, &image)?;
+                if g_buffer {
+                    write_g_buffer(output_dir, i, &frame_g_buffer)?;
+                }
+            }
+            Ok(())
+        },
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render_on_gpu(vulkan_data: &HeadlessVulkanData, chunk: &Chunk, mut build_oct_tree: impl FnMut(&Chunk, Vector3<f64>, &Frustum) -> OctTreeData, cameras: &[CameraData], width: u32, height: u32, output_dir: &Path, g_buffer: bool) -> io::Result<()> {
+    let Some(first_camera) = cameras.first() else {
+        return Ok(());
+    };
+    let camera_data_buffer = create_camera_data_buffer(*first_camera, vulkan_data.allocator.clone());
+    let material_buffer = create_material_buffer(chunk.materials().get_material_data(), vulkan_data.allocator.clone());
+    let render_image_data = create_storage_image(vulkan_data.allocator.clone(), vulkan_data.queue.queue_family_index(), width, height);
+    // Without a G-buffer the shader is created without one as well
+    let g_buffer_data = g_buffer.then(|| create_g_buffer(vulkan_data.allocator.clone(), vulkan_data.queue.queue_family_index(), width, height));
+
+    // The pipeline is created for the layout of the first oct tree
+    let mut pipeline_and_layout = None;
+    for (i, camera) in cameras.iter().enumerate() {
+        *camera_data_buffer.write().unwrap() = *camera;
+        let oct_tree = build_oct_tree(chunk, camera.position.cast(), &camera.frustum(0.0));
+        let (compute_pipeline, layout) = pipeline_and_layout.get_or_insert_with(|| (create_compute_pipeline(vulkan_data.device.clone(), &oct_tree, g_buffer), discriminant(&oct_tree)));
+        if discriminant(&oct_tree) != *layout {
+            panic!("The oct tree layout can not change while rendering");
+        }
+        let voxel_buffer = create_oct_tree_buffer(oct_tree, vulkan_data.allocator.clone());
+        let sets = create_sets(vulkan_data.desc_allocator.clone(), compute_pipeline.layout().set_layouts(), voxel_buffer, camera_data_buffer.clone(), material_buffer.clone(), render_image_data.view.clone(), g_buffer_data.as_ref());
+
+        let mut builder = AutoCommandBufferBuilder::primary(vulkan_data.cmd_allocator.clone().as_ref(), vulkan_data.queue.queue_family_index(), CommandBufferUsage::OneTimeSubmit,).unwrap();
+        builder.bind_pipeline_compute(compute_pipeline.clone())
+               .bind_descriptor_sets(PipelineBindPoint::Compute, compute_pipeline.layout().clone(), 0, sets)
+               .dispatch([width / 8, height / 8, 1]).unwrap()
+               .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(render_image_data.image.clone(), render_image_data.buffer.clone())).unwrap();
+        if let Some(g_buffer_data) = &g_buffer_data {
+            builder.copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(g_buffer_data.depth.image.clone(), g_buffer_data.depth.buffer.clone())).unwrap()
+                   .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(g_buffer_data.normal.image.clone(), g_buffer_data.normal.buffer.clone())).unwrap()
+                   .copy_image_to_buffer(CopyImageToBufferInfo::image_buffer(g_buffer_data.node.image.clone(), g_buffer_data.node.buffer.clone())).unwrap();
+        }
+        let command_buffer = builder.build().unwrap();
+        sync::now(vulkan_data.device.clone())
+            .then_execute(vulkan_data.queue.clone(), command_buffer).unwrap()
+            .then_signal_fence_and_flush().unwrap()
+            .wait(None).unwrap();
+
+        // The compute shaders write blue first, for the swapchain images
+        let pixels = render_image_data.buffer.read().unwrap().chunks(4).flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]]).collect();
+        write_frame(output_dir, i, &RgbaImage { width, height, pixels })?;
+        if let Some(g_buffer_data) = &g_buffer_data {
+            let depth = g_buffer_data.depth.buffer.read().unwrap().chunks(4).map(|bytes| f32::from_ne_bytes(bytes.try_into().unwrap())).collect();
+            // The normals are stored as signed 8 bit values, where 127 is 1
+            let normals = g_buffer_data.normal.buffer.read().unwrap().chunks(4).map(|bytes| Vector3::new(bytes[0], bytes[1], bytes[2]).map(|v| (v as i8 as f32 / 127.0).max(-1.0))).collect();
+            let nodes = g_buffer_data.node.buffer.read().unwrap().chunks(4).map(|bytes| u32::from_ne_bytes(bytes.try_into().unwrap())).collect();
+            write_g_buffer(output_dir, i, &GBuffer { width, height, depth, normals, nodes })?;
+        }
+    }
+    Ok(())
+}
\ No newline at end of file
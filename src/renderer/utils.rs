@@ -1,11 +1,11 @@
 use bytemuck::{Pod, Zeroable};
 use nalgebra::{Matrix4, Vector3, Vector4, Point3};
-use vulkano::buffer::{CpuAccessibleBuffer, BufferUsage};
+use vulkano::buffer::{BufferAccess, CpuAccessibleBuffer, BufferUsage};
 use vulkano::command_buffer::allocator::{StandardCommandBufferAllocator, StandardCommandBufferAllocatorCreateInfo};
 use vulkano::descriptor_set::allocator::{StandardDescriptorSetAllocator};
 use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
 use vulkano::descriptor_set::layout::{DescriptorSetLayout, DescriptorType};
-use vulkano::device::physical::{PhysicalDeviceType};
+use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
 use vulkano::format::Format;
 use vulkano::image::view::{ImageView};
 use vulkano::image::{ImageUsage, SwapchainImage, ImageAccess, ImageViewAbstract, StorageImage, ImageDimensions};
@@ -23,7 +23,12 @@ use winit::window::{WindowBuilder, Window};
 
 use std::sync::Arc;
 
-use crate::voxel::VoxelData;
+use crate::voxel::{CHUNKPOWER, CHUNKSIZE};
+use crate::voxel::frustum::Frustum;
+use crate::voxel::material::MaterialData;
+use crate::voxel::render::Camera;
+
+use super::OctTreeData;
 
 pub struct VulkanData {
     pub surface: Arc<Surface>,
@@ -38,12 +43,31 @@ pub struct VulkanData {
     pub images: Vec<Arc<SwapchainImage>>,
 }
 
+/// The parts of `VulkanData` there is a use for when rendering without a window.
+pub struct HeadlessVulkanData {
+    pub device: Arc<Device>,
+    pub allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>,
+    pub desc_allocator: Arc<StandardDescriptorSetAllocator>,
+    pub cmd_allocator: Arc<StandardCommandBufferAllocator>,
+    pub queue: Arc<Queue>,
+}
+
 pub struct RenderImageData {
     pub image: Arc<StorageImage>,
     pub buffer: Arc<CpuAccessibleBuffer<[u8]>>,
     pub view: Arc<ImageView<StorageImage>>,
 }
 
+/// The images the compute shaders write what the rays hit to for every pixel, next to the color, see `create_g_buffer`.
+pub struct GBufferData {
+    /// The distance from the camera to the hit, infinity where nothing was hit.
+    pub depth: RenderImageData,
+    /// The normal of the face that was hit, zero where nothing was hit.
+    pub normal: RenderImageData,
+    /// Where the voxel that was hit is in the oct tree data, `u32::MAX` where nothing was hit.
+    pub node: RenderImageData,
+}
+
 #[derive(Debug, Copy, Clone, Pod, Zeroable)]
 #[repr(C)]
 pub struct CameraData {
@@ -86,6 +110,16 @@ impl CameraData {
         self.camera_to_world_mat = Self::create_camera_to_world_space(new_forward, new_up, self.position);
     }
 
+    /// The frustum of the camera, `margin` degrees wider on every side, see `Frustum::new`.
+    pub fn frustum(&self, margin: f32) -> Frustum {
+        Frustum::new(self.field_of_view as f32, self.aspect_ratio, self.camera_to_world_mat, margin)
+    }
+
+    /// The camera for `Chunk::render`, which makes its rays the same way as the compute shaders.
+    pub fn camera(&self) -> Camera {
+        Camera { field_of_view: self.field_of_view, aspect_ratio: self.aspect_ratio, camera_to_world: self.camera_to_world_mat, clear_color: self.clear_color }
+    }
+
     pub fn camera_move(&mut self, input: Vector3<f32>, forward_vec: Vector3<f32>, delta_time: f32, movement_speed: f32) {
         let right_vec = forward_vec.cross(&Vector3::new(0.0, 1.0, 0.0)).normalize();
         if input.x > 0.0 {
@@ -146,16 +180,7 @@ pub fn setup_vulkan(event_loop: &EventLoop<()>) -> VulkanData {
                 })
                 .map(|i| (p, i as u32))
         })
-        .min_by_key(|(p, _)| {
-            match p.properties().device_type {
-                PhysicalDeviceType::DiscreteGpu => 0,
-                PhysicalDeviceType::IntegratedGpu => 1,
-                PhysicalDeviceType::VirtualGpu => 2,
-                PhysicalDeviceType::Cpu => 3,
-                PhysicalDeviceType::Other => 4,
-                _ => 5,
-            }
-        })
+        .min_by_key(|(p, _)| device_priority(p))
         .expect("No suitable physical device found");
 
     println!("Using {}", physical_device.clone().properties().device_name);
@@ -195,17 +220,95 @@ pub fn setup_vulkan(event_loop: &EventLoop<()>) -> VulkanData {
         ).unwrap()
     };
 
-    VulkanData {surface: surface, window: window, instance: instance.clone(), device: device.clone(), allocator: Arc::new(GenericMemoryAllocator::new(device.clone(), GenericMemoryAllocatorCreateInfo {block_sizes: &[(0 as Threshold, 199_999_999 as BlockSize)], allocation_type: AllocationType::Unknown, ..Default::default()}).unwrap()), desc_allocator: Arc::new(StandardDescriptorSetAllocator::new(device.clone())), cmd_allocator: Arc::new(StandardCommandBufferAllocator::new(device.clone(), StandardCommandBufferAllocatorCreateInfo {..Default::default()})),queue: queue.clone(), swapchain: swapchain, images: images }
+    VulkanData {surface: surface, window: window, instance: instance.clone(), device: device.clone(), allocator: create_memory_allocator(device.clone()), desc_allocator: Arc::new(StandardDescriptorSetAllocator::new(device.clone())), cmd_allocator: Arc::new(StandardCommandBufferAllocator::new(device.clone(), StandardCommandBufferAllocatorCreateInfo {..Default::default()})),queue: queue.clone(), swapchain: swapchain, images: images }
+}
+
+/// Which kinds of devices to render with first, lower is better.
+fn device_priority(physical_device: &PhysicalDevice) -> u32 {
+    match physical_device.properties().device_type {
+        PhysicalDeviceType::DiscreteGpu => 0,
+        PhysicalDeviceType::IntegratedGpu => 1,
+        PhysicalDeviceType::VirtualGpu => 2,
+        PhysicalDeviceType::Cpu => 3,
+        PhysicalDeviceType::Other => 4,
+        _ => 5,
+    }
+}
+
+fn create_memory_allocator(device: Arc<Device>) -> Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>> {
+    Arc::new(GenericMemoryAllocator::new(device, GenericMemoryAllocatorCreateInfo {block_sizes: &[(0 as Threshold, 199_999_999 as BlockSize)], allocation_type: AllocationType::Unknown, ..Default::default()}).unwrap())
+}
+
+/// Sets up a device for the compute shaders without a window or a surface, for rendering to files.
+/// Returns `None` if there is no Vulkan or no device that can run compute shaders, instead of panicking like `setup_vulkan`.
+pub fn setup_headless_vulkan() -> Option<HeadlessVulkanData> {
+    let lib = VulkanLibrary::new().ok()?;
+    let instance = Instance::new(lib, InstanceCreateInfo {
+        enumerate_portability: true,
+        ..Default::default()
+    }).ok()?;
+
+    let device_extensions = DeviceExtensions {
+        khr_storage_buffer_storage_class: true,
+        ..DeviceExtensions::empty()
+    };
+
+    let (physical_device, queue_family_index) = instance
+        .enumerate_physical_devices()
+        .ok()?
+        .filter(|p| {
+            p.supported_extensions().contains(&device_extensions)
+        }).filter_map(|p| {
+            p.queue_family_properties().iter().position(|q| q.queue_flags.compute).map(|i| (p, i as u32))
+        })
+        .min_by_key(|(p, _)| device_priority(p))?;
+
+    println!("Using {}", physical_device.properties().device_name);
+
+    let (device, mut queues) = Device::new(
+        physical_device,
+        DeviceCreateInfo {
+            enabled_extensions: device_extensions,
+            queue_create_infos: vec![QueueCreateInfo {queue_family_index, ..Default::default()}],
+            ..Default::default()
+        },
+    ).ok()?;
+    let queue = queues.next()?;
+
+    Some(HeadlessVulkanData {
+        device: device.clone(),
+        allocator: create_memory_allocator(device.clone()),
+        desc_allocator: Arc::new(StandardDescriptorSetAllocator::new(device.clone())),
+        cmd_allocator: Arc::new(StandardCommandBufferAllocator::new(device, StandardCommandBufferAllocatorCreateInfo {..Default::default()})),
+        queue,
+    })
 }
 
 pub fn create_camera_data_buffer(data: CameraData, allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>) -> Arc<CpuAccessibleBuffer<CameraData>> {
     CpuAccessibleBuffer::from_data(allocator.as_ref(), BufferUsage {storage_buffer: true, ..BufferUsage::empty()}, false, data).unwrap()
 }
 
-pub fn create_voxel_buffer(data: Vec<VoxelData>, allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>) -> Arc<CpuAccessibleBuffer<[VoxelData]>> {
+/// The material table is never empty on the GPU, since a storage buffer can not be empty. Voxels never refer to the added material.
+pub fn create_material_buffer(mut data: Vec<MaterialData>, allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>) -> Arc<CpuAccessibleBuffer<[MaterialData]>> {
+    if data.is_empty() {
+        data.push(MaterialData::zeroed());
+    }
     CpuAccessibleBuffer::from_iter(allocator.as_ref(), BufferUsage {storage_buffer: true, ..BufferUsage::empty()}, false, data.into_iter()).unwrap()
 }
 
+/// Works for the full `VoxelData` and the `CompactVoxelData` layout, and for the words of a `VoxelDag`.
+pub fn create_voxel_buffer<T: Pod + Send + Sync>(data: Vec<T>, allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>) -> Arc<CpuAccessibleBuffer<[T]>> {
+    CpuAccessibleBuffer::from_iter(allocator.as_ref(), BufferUsage {storage_buffer: true, ..BufferUsage::empty()}, false, data.into_iter()).unwrap()
+}
+
+pub fn create_oct_tree_buffer(oct_tree: OctTreeData, allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>) -> Arc<dyn BufferAccess> {
+    match oct_tree {
+        OctTreeData::Full(voxel_data) => create_voxel_buffer(voxel_data, allocator),
+        OctTreeData::Compact(voxel_data) => create_voxel_buffer(voxel_data, allocator),
+        OctTreeData::Dag(dag) => create_voxel_buffer(dag.into_words(), allocator),
+    }
+}
+
 pub fn recreate_swapchain(vulkan_data: &mut VulkanData, dim: PhysicalSize<u32>) {
     let (new_sc, new_imgs) = match vulkan_data.swapchain.recreate(SwapchainCreateInfo {image_extent: dim.into(), ..vulkan_data.swapchain.create_info()}) {
         Ok(r) => r,
@@ -218,7 +321,7 @@ pub fn recreate_swapchain(vulkan_data: &mut VulkanData, dim: PhysicalSize<u32>)
 
 }
 
-pub fn create_sets(desc_allocator: Arc<StandardDescriptorSetAllocator>, set_layouts: &[Arc<DescriptorSetLayout>], voxel_buffer: Arc<CpuAccessibleBuffer<[VoxelData]>>, misc_buffer: Arc<CpuAccessibleBuffer<CameraData>>, img_view: Arc<dyn ImageViewAbstract>) -> Vec<Arc<PersistentDescriptorSet>> {
+pub fn create_sets(desc_allocator: Arc<StandardDescriptorSetAllocator>, set_layouts: &[Arc<DescriptorSetLayout>], voxel_buffer: Arc<dyn BufferAccess>, misc_buffer: Arc<CpuAccessibleBuffer<CameraData>>, material_buffer: Arc<CpuAccessibleBuffer<[MaterialData]>>, img_view: Arc<dyn ImageViewAbstract>, g_buffer: Option<&GBufferData>) -> Vec<Arc<PersistentDescriptorSet>> {
     let mut sets = vec![];
 
     for set_layout in set_layouts {
@@ -230,10 +333,14 @@ pub fn create_sets(desc_allocator: Arc<StandardDescriptorSetAllocator>, set_layo
             }
 
             if x.1.descriptor_type == DescriptorType::StorageBuffer {
-                sets.push(PersistentDescriptorSet::new(desc_allocator.clone().as_ref(), set_layout.clone(), [WriteDescriptorSet::buffer(0, voxel_buffer.clone()), WriteDescriptorSet::buffer(1, misc_buffer.clone())]).unwrap());
+                sets.push(PersistentDescriptorSet::new(desc_allocator.clone().as_ref(), set_layout.clone(), [WriteDescriptorSet::buffer(0, voxel_buffer.clone()), WriteDescriptorSet::buffer(1, misc_buffer.clone()), WriteDescriptorSet::buffer(2, material_buffer.clone())]).unwrap());
                 
-            } else if x.1.descriptor_type == DescriptorType::StorageImage {
+            } else if x.1.descriptor_type == DescriptorType::StorageImage && set_layout.bindings().len() == 1 {
                 sets.push(PersistentDescriptorSet::new(desc_allocator.clone().as_ref(), set_layout.clone(), [WriteDescriptorSet::image_view(0, img_view.clone())]).unwrap())
+            } else if x.1.descriptor_type == DescriptorType::StorageImage {
+                // The G-buffer is the only set with more than one image, and only the shaders created with a G-buffer have it
+                let g_buffer = g_buffer.expect("create_sets(): The shader has a G-buffer, but no G-buffer was given");
+                sets.push(PersistentDescriptorSet::new(desc_allocator.clone().as_ref(), set_layout.clone(), [WriteDescriptorSet::image_view(0, g_buffer.depth.view.clone()), WriteDescriptorSet::image_view(1, g_buffer.normal.view.clone()), WriteDescriptorSet::image_view(2, g_buffer.node.view.clone())]).unwrap())
             } else {
                 panic!("There exists an unused descriptorset, it should be implemented!");
             }
@@ -246,19 +353,39 @@ pub fn create_sets(desc_allocator: Arc<StandardDescriptorSetAllocator>, set_layo
 }
 
 pub fn create_render_image(vulkan_data: &mut VulkanData) -> RenderImageData {
+    let dimensions = vulkan_data.images[0].dimensions();
+    create_storage_image(vulkan_data.allocator.clone(), vulkan_data.queue.queue_family_index(), dimensions.width(), dimensions.height())
+}
+
+/// The image the compute shaders render to, and a buffer of the same size to copy it through.
+pub fn create_storage_image(allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>, queue_family_index: u32, width: u32, height: u32) -> RenderImageData {
+    create_image(allocator, queue_family_index, width, height, Format::R8G8B8A8_UNORM)
+}
+
+/// The G-buffer for images with the size `width` times `height`, for the shaders created with `g_buffer`.
+pub fn create_g_buffer(allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>, queue_family_index: u32, width: u32, height: u32) -> GBufferData {
+    GBufferData {
+        depth: create_image(allocator.clone(), queue_family_index, width, height, Format::R32_SFLOAT),
+        normal: create_image(allocator.clone(), queue_family_index, width, height, Format::R8G8B8A8_SNORM),
+        node: create_image(allocator, queue_family_index, width, height, Format::R32_UINT),
+    }
+}
+
+// Every format used here has 4 bytes per pixel
+fn create_image(allocator: Arc<GenericMemoryAllocator<Arc<FreeListAllocator>>>, queue_family_index: u32, width: u32, height: u32, format: Format) -> RenderImageData {
     let image = StorageImage::new(
-        vulkan_data.allocator.clone().as_ref(),
+        allocator.as_ref(),
         ImageDimensions::Dim2d {
-            width: vulkan_data.images[0].dimensions().width(),
-            height: vulkan_data.images[0].dimensions().height(),
+            width,
+            height,
             array_layers: 1,
         }, 
-        Format::R8G8B8A8_UNORM,
-        Some(vulkan_data.queue.clone().queue_family_index()),
+        format,
+        Some(queue_family_index),
     ).unwrap();
 
     let buffer = CpuAccessibleBuffer::from_iter(
-        vulkan_data.allocator.clone().as_ref(),
+        allocator.as_ref(),
         BufferUsage {
             transfer_src: true,
             transfer_dst: true,
@@ -266,7 +393,7 @@ pub fn create_render_image(vulkan_data: &mut VulkanData) -> RenderImageData {
             ..Default::default()
         },
         false,
-        (0..vulkan_data.images[0].dimensions().width() * vulkan_data.images[0].dimensions().height() * 4).map(|_| 0u8)
+        (0..width * height * 4).map(|_| 0u8)
     ).unwrap();
 
     let view = ImageView::new_default(image.clone()).unwrap();
@@ -274,353 +401,45 @@ pub fn create_render_image(vulkan_data: &mut VulkanData) -> RenderImageData {
     RenderImageData { image, buffer, view }
 }
 
+// The ray tracing shaders for every layout, which share everything but how they look at the nodes. CHUNKSIZE and MAX_DEPTH have to be
+// literals here, they are checked against the constants they come from below.
+mod cs {
+    vulkano_shaders::shader! {
+        shaders: {
+            full: { ty: "compute", path: "resources/shaders/ray_tracer.comp" },
+            compact: { ty: "compute", path: "resources/shaders/ray_tracer_compact.comp" },
+            dag: { ty: "compute", path: "resources/shaders/ray_tracer_dag.comp" },
+        },
+        define: [("CHUNKSIZE", "65536"), ("MAX_DEPTH", "16")]
+    }
+}
 
+// The same shaders, but they also write what the rays hit to the G-buffer in set 2
+mod cs_g_buffer {
+    vulkano_shaders::shader! {
+        shaders: {
+            full: { ty: "compute", path: "resources/shaders/ray_tracer.comp" },
+            compact: { ty: "compute", path: "resources/shaders/ray_tracer_compact.comp" },
+            dag: { ty: "compute", path: "resources/shaders/ray_tracer_dag.comp" },
+        },
+        define: [("CHUNKSIZE", "65536"), ("MAX_DEPTH", "16"), ("G_BUFFER", "1")]
+    }
+}
 
+const _: () = assert!(CHUNKSIZE == 65536 && CHUNKPOWER * 2 == 16, "the CHUNKSIZE and MAX_DEPTH defines of the shaders do not match CHUNKPOWER");
 
+/// The shader for the full octree layout, see `VoxelData`. With `g_buffer` it also writes what the rays hit to the G-buffer in set 2,
+/// without it the shader has no G-buffer and set 2 does not have to be bound.
+pub fn create_main_shader(device: Arc<Device>, g_buffer: bool) -> Arc<ShaderModule> {
+    if g_buffer { cs_g_buffer::load_full(device) } else { cs::load_full(device) }.unwrap()
+}
 
+/// The shader for the compact octree layout, see `CompactVoxelData`. It uses the same descriptor sets as the main shader.
+pub fn create_compact_shader(device: Arc<Device>, g_buffer: bool) -> Arc<ShaderModule> {
+    if g_buffer { cs_g_buffer::load_compact(device) } else { cs::load_compact(device) }.unwrap()
+}
 
-
-
-
-pub fn create_main_shader(device: Arc<Device>) -> Arc<ShaderModule> {
-    mod cs {
-        vulkano_shaders::shader! {
-            ty: "compute",
-            src: "
-            #version 450
-            layout(local_size_x = 8, local_size_y = 8, local_size_z = 1) in;
-            
-            struct VoxelData
-            {
-                vec2 pos_xy;
-                // The range value is the pos_zw.y value this is done to save space
-                vec2 pos_zw;
-                vec2 color_rg;
-                vec2 color_ba;
-                uint _0_0_index;
-                uint _0_1_index;
-                uint _0_2_index;
-                uint _0_3_index;
-                uint _1_0_index;
-                uint _1_1_index;
-                uint _1_2_index;
-                uint _1_3_index;
-            };
-            
-            layout(set = 0, binding = 0) readonly buffer Data {
-                VoxelData data[];
-            } voxel_data;
-            
-            layout(set = 0, binding = 1)  readonly buffer CameraData {
-                uint field_of_view;
-                float render_distance;
-                float aspectRatio;
-                float fov_tan;
-                mat4 camera_to_world;
-                vec4 clear_color;
-            } camera;
-            
-            layout(set = 1, binding = 0, rgba8) uniform image2D img_out; 
-            
-            struct ColorHit {
-                bool hit;
-                vec4 color;
-            };
-            
-            struct Ray {
-                vec3 origin;
-                vec3 direction;
-            };
-            
-            struct RayHit {
-                bool hit;
-                vec3 normal;
-                vec2 result;
-            };
-            
-            // Const variables
-            const uint UINT_MAX = -1;
-            const float INFINITY_F = 1.0/0.0;
-            
-            // Helper functions
-            
-            float max_component(vec3 vec) {
-                return max(max(vec.x, vec.y), vec.z);
-            }
-            
-            float min_component(vec3 vec) {
-                return min(min(vec.x, vec.y), vec.z);
-            }
-            
-            
-            // From https://jcgt.org/published/0007/03/04/
-            bool slabs(VoxelData voxel, Ray ray, vec3 invRaydir) {
-                const vec3 p0 = vec3(voxel.pos_xy, voxel.pos_zw.x);
-                const vec3 p1 = vec3(voxel.pos_xy.x + voxel.pos_zw.y, voxel.pos_xy.y + voxel.pos_zw.y, voxel.pos_zw.x + voxel.pos_zw.y);
-            
-                const vec3 t0 = (p0 - ray.origin) * invRaydir;
-                const vec3 t1 = (p1 - ray.origin) * invRaydir;
-                const vec3 tmin = min(t0,t1), tmax = max(t0,t1);
-                const float tmax_val = min_component(tmax);
-                const float tmin_val = max_component(tmin);
-                // TODO: Make the tmax_val also work with vectors in the negative space
-                return tmin_val <= tmax_val && tmax_val >= 0.0;
-            }
-            
-            uint[8] get_children_indices(VoxelData voxel) {
-                return uint[8](voxel._0_0_index, voxel._0_1_index, voxel._0_2_index, voxel._0_3_index, voxel._1_0_index, voxel._1_1_index, voxel._1_2_index, voxel._1_3_index);
-            }
-            
-            bool is_leaf_node(VoxelData voxel) {
-                return voxel._0_0_index == uint(-1) && voxel._0_1_index == uint(-1) &&
-                       voxel._0_2_index == uint(-1) && voxel._0_3_index == uint(-1) &&
-                       voxel._1_0_index == uint(-1) && voxel._1_1_index == uint(-1) &&
-                       voxel._1_2_index == uint(-1) && voxel._1_3_index == uint(-1);
-            }
-            
-            ColorHit fill_hit_color(VoxelData voxel) {
-                ColorHit data;
-                data.color = vec4(voxel.color_rg, voxel.color_ba);
-                data.hit = true;
-                return data;
-            }
-            
-            float get_distance(Ray ray, VoxelData voxel) {
-                // TODO: there is something wrong here
-                return length(vec3((voxel.pos_xy.x+voxel.pos_zw.y)/2.0, (voxel.pos_xy.y+voxel.pos_zw.y)/2.0, (voxel.pos_zw.x+voxel.pos_zw.y)/2.0) - ray.origin);
-            }
-            
-            bool is_closer(in Ray ray, in VoxelData voxel, in float closest) {
-                return get_distance(ray, voxel) < closest;
-            }
-            
-            ColorHit voxel_hit(Ray ray, vec4 clear_col) {
-                ColorHit ret_val;
-                ret_val.color = clear_col;
-                ret_val.hit = false;
-                float closest = 999999999999999999.0;
-                const vec3 invRaydir = 1.0/ray.direction;
-                
-                // Look here for tip on how to find the intersection/hit point: https://tavianator.com/2011/ray_box.html
-                // GLSL does not allow for recursive functions, thus it needs to be hard-coded
-                VoxelData temp_voxel = voxel_data.data[voxel_data.data.length()-1];
-                const uint[8] level_0 = get_children_indices(temp_voxel);
-                if (!slabs(temp_voxel, ray, invRaydir)) return ret_val;
-                if (is_leaf_node(temp_voxel)) return fill_hit_color(temp_voxel);
-                for (int i_0 = 0; i_0 < level_0.length(); i_0++) {
-                    if (level_0[i_0] == UINT_MAX) continue;
-                    temp_voxel = voxel_data.data[level_0[i_0]];
-                    const uint[8] level_1 = get_children_indices(temp_voxel);
-                    if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                    if (is_leaf_node(temp_voxel)) {
-                        ret_val = fill_hit_color(temp_voxel);
-                        closest = get_distance(ray, temp_voxel);
-                        continue;
-                    }
-                    for (int i_1 = 0; i_1 < level_1.length(); i_1++) {
-                        if (level_1[i_1]== UINT_MAX) continue;
-                        temp_voxel = voxel_data.data[level_1[i_1]];
-                        const uint[8] level_2 = get_children_indices(temp_voxel);
-                        if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                        if (is_leaf_node(temp_voxel)) {
-                            ret_val = fill_hit_color(temp_voxel);
-                            closest = get_distance(ray, temp_voxel);
-                            continue;
-                        }
-                        for (int i_2 = 0; i_2 < level_2.length(); i_2++) {
-                            if (level_2[i_2]== UINT_MAX) continue;
-                            temp_voxel = voxel_data.data[level_2[i_2]];
-                            const uint[8] level_3 = get_children_indices(temp_voxel);
-                            if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                            if (is_leaf_node(temp_voxel)) {
-                                ret_val = fill_hit_color(temp_voxel);
-                                closest = get_distance(ray, temp_voxel);
-                                continue;
-                            }
-                            for (int i_3 = 0; i_3 < level_3.length(); i_3++) {
-                                if (level_3[i_3]== UINT_MAX) continue;
-                                temp_voxel = voxel_data.data[level_3[i_3]];
-                                const uint[8] level_4 = get_children_indices(temp_voxel);
-                                if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                if (is_leaf_node(temp_voxel)) {
-                                    ret_val = fill_hit_color(temp_voxel);
-                                    closest = get_distance(ray, temp_voxel);
-                                    continue;
-                                }
-                                for (int i_4 = 0; i_4 < level_4.length(); i_4++) {
-                                    if (level_4[i_4]== UINT_MAX) continue;
-                                    temp_voxel = voxel_data.data[level_4[i_4]];
-                                    const uint[8] level_5 = get_children_indices(temp_voxel);
-                                    if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                    if (is_leaf_node(temp_voxel)) {
-                                        ret_val = fill_hit_color(temp_voxel);
-                                        closest = get_distance(ray, temp_voxel);
-                                        continue;
-                                    }
-                                    for (int i_5 = 0; i_5 < level_5.length(); i_5++) {
-                                        if (level_5[i_5]== UINT_MAX) continue;
-                                        temp_voxel = voxel_data.data[level_5[i_5]];
-                                        const uint[8] level_6 = get_children_indices(temp_voxel);
-                                        if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                        if (is_leaf_node(temp_voxel)) {
-                                            ret_val = fill_hit_color(temp_voxel);
-                                            closest = get_distance(ray, temp_voxel);
-                                            continue;
-                                        }
-                                        for (int i_6 = 0; i_6 < level_6.length(); i_6++) {
-                                            if (level_6[i_6]== UINT_MAX) continue;
-                                            temp_voxel = voxel_data.data[level_6[i_6]];
-                                            const uint[8] level_7 = get_children_indices(temp_voxel);
-                                            if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                            if (is_leaf_node(temp_voxel)) {
-                                                ret_val = fill_hit_color(temp_voxel);
-                                                closest = get_distance(ray, temp_voxel);
-                                                continue;
-                                            }
-                                            for (int i_7 = 0; i_7 < level_7.length(); i_7++) {
-                                                if (level_7[i_7]== UINT_MAX) continue;
-                                                temp_voxel = voxel_data.data[level_7[i_7]];
-                                                const uint[8] level_8 = get_children_indices(temp_voxel);
-                                                if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                if (is_leaf_node(temp_voxel)) {
-                                                    ret_val = fill_hit_color(temp_voxel);
-                                                    closest = get_distance(ray, temp_voxel);
-                                                    continue;
-                                                }
-                                                for (int i_8 = 0; i_8 < level_8.length(); i_8++) {
-                                                    if (level_8[i_8]== UINT_MAX) continue;
-                                                    temp_voxel = voxel_data.data[level_8[i_8]];
-                                                    const uint[8] level_9 = get_children_indices(temp_voxel);
-                                                    if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                    if (is_leaf_node(temp_voxel)) {
-                                                        ret_val = fill_hit_color(temp_voxel);
-                                                        closest = get_distance(ray, temp_voxel);
-                                                        continue;
-                                                    }
-                                                    for (int i_9 = 0; i_9 < level_9.length(); i_9++) {
-                                                        if (level_9[i_9] == UINT_MAX) continue;
-                                                        temp_voxel = voxel_data.data[level_9[i_9]];
-                                                        const uint[8] level_10 = get_children_indices(temp_voxel);
-                                                        if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                        if (is_leaf_node(temp_voxel)) {
-                                                            ret_val = fill_hit_color(temp_voxel);
-                                                            closest = get_distance(ray, temp_voxel);
-                                                            continue;
-                                                        }
-                                                        for (int i_10 = 0; i_10 < level_10.length(); i_10++) {
-                                                            if (level_10[i_10] == UINT_MAX) continue;
-                                                            temp_voxel = voxel_data.data[level_10[i_10]];
-                                                            const uint[8] level_11 = get_children_indices(temp_voxel);
-                                                            if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                            if (is_leaf_node(temp_voxel)) {
-                                                                ret_val = fill_hit_color(temp_voxel);
-                                                                closest = get_distance(ray, temp_voxel);
-                                                                continue;
-                                                            }
-                                                            for (int i_11 = 0; i_11 < level_11.length(); i_11++) {
-                                                                if (level_11[i_11] == UINT_MAX) continue;
-                                                                temp_voxel = voxel_data.data[level_11[i_11]];
-                                                                const uint[8] level_12 = get_children_indices(temp_voxel);
-                                                                if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                if (is_leaf_node(temp_voxel)) {
-                                                                    ret_val = fill_hit_color(temp_voxel);
-                                                                    closest = get_distance(ray, temp_voxel);
-                                                                    continue;
-                                                                }
-                                                                for (int i_12 = 0; i_12 < level_12.length(); i_12++) {
-                                                                    if (level_12[i_12] == UINT_MAX) continue;
-                                                                    temp_voxel = voxel_data.data[level_12[i_12]];
-                                                                    const uint[8] level_13 = get_children_indices(temp_voxel);
-                                                                    if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                    if (is_leaf_node(temp_voxel)) {
-                                                                        ret_val = fill_hit_color(temp_voxel);
-                                                                        closest = get_distance(ray, temp_voxel);
-                                                                        continue;
-                                                                    }
-                                                                    for (int i_13 = 0; i_13 < level_13.length(); i_13++) {
-                                                                        if (level_13[i_13] == UINT_MAX) continue;
-                                                                        temp_voxel = voxel_data.data[level_13[i_13]];
-                                                                        const uint[8] level_14 = get_children_indices(temp_voxel);
-                                                                        if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                        if (is_leaf_node(temp_voxel)) {
-                                                                            ret_val = fill_hit_color(temp_voxel);
-                                                                            closest = get_distance(ray, temp_voxel);
-                                                                            continue;
-                                                                        }
-                                                                        for (int i_14 = 0; i_14 < level_14.length(); i_14++) {
-                                                                            if (level_14[i_14] == UINT_MAX) continue;
-                                                                            temp_voxel = voxel_data.data[level_14[i_14]];
-                                                                            const uint[8] level_15 = get_children_indices(temp_voxel);
-                                                                            if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                            if (is_leaf_node(temp_voxel)) {
-                                                                                ret_val = fill_hit_color(temp_voxel);
-                                                                                closest = get_distance(ray, temp_voxel);
-                                                                                continue;
-                                                                            }
-                                                                            for (int i_15 = 0; i_15 < level_15.length(); i_15++) {
-                                                                                if (level_15[i_15] == UINT_MAX) continue;
-                                                                                temp_voxel = voxel_data.data[level_15[i_15]];
-                                                                                const uint[8] level_16 = get_children_indices(temp_voxel);
-                                                                                if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                                if (is_leaf_node(temp_voxel)) {
-                                                                                    ret_val = fill_hit_color(temp_voxel);
-                                                                                    closest = get_distance(ray, temp_voxel);
-                                                                                    continue;
-                                                                                }
-                                                                                for (int i_16 = 0; i_16 < level_16.length(); i_16++) {
-                                                                                    if (level_16[i_16] == UINT_MAX) continue;
-                                                                                    temp_voxel = voxel_data.data[level_16[i_16]];
-                                                                                    if (!slabs(temp_voxel, ray, invRaydir) || !is_closer(ray, temp_voxel, closest)) continue;
-                                                                                    if (is_leaf_node(temp_voxel)) {
-                                                                                        ret_val = fill_hit_color(temp_voxel);
-                                                                                        closest = get_distance(ray, temp_voxel);
-                                                                                        continue;
-                                                                                    }
-                                                                                }
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            
-                return ret_val;
-            }
-            
-            
-            // Main         
-            void main() {
-                ivec2 IDxy = ivec2(gl_GlobalInvocationID.xy);
-                
-                const ivec2 screenSize = imageSize(img_out);
-                const vec2 pixel_NCD = vec2((float(IDxy.x)+0.5)/float(screenSize.x), (float(IDxy.y)+0.5)/float(screenSize.y));
-                const vec2 camera_pixel = vec2((2 * pixel_NCD.x - 1) * camera.aspectRatio * camera.fov_tan, (1 - 2 * pixel_NCD.y) * camera.fov_tan);
-            
-                const highp vec4 world_search_pos = vec4(vec3(camera_pixel.x, camera_pixel.y, -1.0), 0.0)*camera.camera_to_world;
-                highp vec3 current_search_pos = normalize(world_search_pos.xyz);
-                current_search_pos.x = -current_search_pos.x;
-                vec4 color_in_the_end = camera.clear_color;
-            
-                const Ray ray = Ray(vec3(camera.camera_to_world[3].x, camera.camera_to_world[3].y, camera.camera_to_world[3].z), normalize(current_search_pos));
-                
-                ColorHit check = voxel_hit(ray, camera.clear_color);
-                if (check.hit) color_in_the_end = check.color;
-            
-                imageStore(img_out, IDxy, vec4(color_in_the_end.b, color_in_the_end.g, color_in_the_end.r, color_in_the_end.a));
-            }
-            "
-        }
-    }
-    
-    cs::load(device).unwrap()
-}
\ No newline at end of file
+/// The shader for the sparse voxel DAG layout, see `VoxelDag`. It uses the same descriptor sets as the main shader.
+pub fn create_dag_shader(device: Arc<Device>, g_buffer: bool) -> Arc<ShaderModule> {
+    if g_buffer { cs_g_buffer::load_dag(device) } else { cs::load_dag(device) }.unwrap()
+}